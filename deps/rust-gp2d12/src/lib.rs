@@ -1,5 +1,8 @@
 extern crate mcp3008;
 
+use std::cmp::Ordering;
+use std::f32;
+
 use mcp3008::{Mcp3008, Mcp3008Error};
 
 const A_F1: f32 = 26.0 / 44625.0;
@@ -10,27 +13,323 @@ const A_F2: f32 = 17.0 / 316848.0;
 const B_F2: f32 = -14761.0 / 158424.0;
 const C_F2: f32 = 307535.0 / 6601.0;
 
+fn nominal(raw_value: u32) -> f32 {
+	let raw_value_f32 = raw_value as f32;
+
+	if raw_value < 360 {
+		raw_value.pow(2) as f32 * A_F1 + raw_value_f32 * B_F1 + C_F1
+	} else {
+		raw_value.pow(2) as f32 * A_F2 + raw_value_f32 * B_F2 + C_F2
+	}
+}
+
+/// Conversion of raw ADC counts to centimetres
+#[derive(Clone, PartialEq, Debug)]
+pub enum Calibration {
+	/// Fit of the datasheet curve, two quadratics split at 360 counts
+	Nominal,
+	/// Polynomial fitted between `min` and `max` raw counts, evaluated in
+	/// counts scaled to -1 to 1 over that range. Coefficients are highest
+	/// degree first, counts outside the range are clamped to it.
+	Polynomial {
+		coefficients: Vec<f64>,
+		min: f32,
+		max: f32,
+	},
+	/// Raw counts and centimetres sorted by counts, interpolated linearly
+	/// and clamped to the outermost points
+	Table(Vec<(f32, f32)>),
+}
+
+// Maps `min` to `max` counts onto -1 to 1, keeps the powers of the counts in
+// the normal equations within the precision of f64
+fn scaled(raw: f32, min: f32, max: f32) -> f64 {
+	(2.0 * raw as f64 - (min as f64 + max as f64)) / (max as f64 - min as f64)
+}
+
+impl Default for Calibration {
+	fn default() -> Calibration {
+		Calibration::Nominal
+	}
+}
+
+impl Calibration {
+	/// Table of measured points in any order, `None` with fewer than two
+	pub fn table(points: &[(f32, f32)]) -> Option<Calibration> {
+		if points.len() < 2 {
+			return None;
+		}
+
+		let mut points = points.to_vec();
+		points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+		Some(Calibration::Table(points))
+	}
+
+	/// Least squares fit of a polynomial of `degree` to measured points,
+	/// `None` if the points do not determine it
+	pub fn fit(points: &[(f32, f32)], degree: usize) -> Option<Calibration> {
+		let size = degree + 1;
+
+		if points.len() < size {
+			return None;
+		}
+
+		let min = points
+			.iter()
+			.map(|&(raw, _)| raw)
+			.fold(f32::INFINITY, f32::min);
+		let max = points
+			.iter()
+			.map(|&(raw, _)| raw)
+			.fold(f32::NEG_INFINITY, f32::max);
+
+		if !(max > min && max.is_finite() && min.is_finite()) {
+			return None;
+		}
+
+		// Normal equations, the coefficients are solved lowest degree first
+		let mut matrix = vec![vec![0.0_f64; size + 1]; size];
+
+		for &(raw, distance) in points {
+			let raw = scaled(raw, min, max);
+			let powers: Vec<f64> = (0..2 * size)
+				.map(|power| raw.powi(power as i32))
+				.collect();
+
+			for row in 0..size {
+				for column in 0..size {
+					matrix[row][column] += powers[row + column];
+				}
+
+				matrix[row][size] += powers[row] * distance as f64;
+			}
+		}
+
+		// Entries of the scaled matrix are at most the number of points
+		let tolerance = 1e-10 * points.len() as f64;
+
+		// Gaussian elimination with partial pivoting
+		for column in 0..size {
+			let pivot = (column..size).max_by(|&a, &b| {
+				matrix[a][column]
+					.abs()
+					.partial_cmp(&matrix[b][column].abs())
+					.unwrap_or(Ordering::Equal)
+			})?;
+
+			if matrix[pivot][column].abs() < tolerance {
+				return None;
+			}
+
+			matrix.swap(column, pivot);
+
+			for row in column + 1..size {
+				let factor = matrix[row][column] / matrix[column][column];
+
+				for index in column..size + 1 {
+					matrix[row][index] -= factor * matrix[column][index];
+				}
+			}
+		}
+
+		let mut coefficients = vec![0.0; size];
+
+		for row in (0..size).rev() {
+			let known: f64 = (row + 1..size)
+				.map(|column| matrix[row][column] * coefficients[column])
+				.sum();
+
+			coefficients[row] = (matrix[row][size] - known) / matrix[row][row];
+		}
+
+		coefficients.reverse();
+
+		Some(Calibration::Polynomial {
+			coefficients: coefficients,
+			min: min,
+			max: max,
+		})
+	}
+
+	pub fn distance(&self, raw: u16) -> f32 {
+		match *self {
+			Calibration::Nominal => nominal(raw as u32),
+			Calibration::Polynomial {
+				ref coefficients,
+				min,
+				max,
+			} => {
+				let raw = scaled((raw as f32).max(min).min(max), min, max);
+
+				coefficients
+					.iter()
+					.fold(0.0, |value, coefficient| value * raw + coefficient)
+					as f32
+			}
+			Calibration::Table(ref points) => {
+				let raw = raw as f32;
+
+				match points.iter().position(|&(counts, _)| counts >= raw) {
+					Some(0) => points[0].1,
+					// The previous point lies below `raw`
+					Some(index) => {
+						let (x0, y0) = points[index - 1];
+						let (x1, y1) = points[index];
+
+						y0 + (y1 - y0) * (raw - x0) / (x1 - x0)
+					}
+					None => points
+						.last()
+						.map(|&(_, distance)| distance)
+						.unwrap_or(0.0),
+				}
+			}
+		}
+	}
+}
+
 pub struct Gp2d12 {
 	adc_number: u8,
 	mcp3008: Mcp3008,
+	calibration: Calibration,
 }
 
 impl Gp2d12 {
 	pub fn new(mcp3008: Mcp3008, adc_number: u8) -> Gp2d12 {
+		Gp2d12::with_calibration(mcp3008, adc_number, Calibration::Nominal)
+	}
+
+	pub fn with_calibration(
+		mcp3008: Mcp3008,
+		adc_number: u8,
+		calibration: Calibration,
+	) -> Gp2d12 {
 		Gp2d12 {
 			adc_number: adc_number,
 			mcp3008: mcp3008,
+			calibration: calibration,
 		}
 	}
 
+	/// Uncalibrated ADC counts
+	pub fn read_raw(&mut self) -> Result<u16, Mcp3008Error> {
+		Ok(self.mcp3008.read_adc(self.adc_number)? as u16)
+	}
+
 	pub fn read(&mut self) -> Result<f32, Mcp3008Error> {
-		let raw_value = self.mcp3008.read_adc(self.adc_number)? as u32;
-		let raw_value_f32 = raw_value as f32;
+		let raw_value = self.read_raw()?;
 
-		Ok(if raw_value < 360 {
-			raw_value.pow(2) as f32 * A_F1 + raw_value_f32 * B_F1 + C_F1
-		} else {
-			raw_value.pow(2) as f32 * A_F2 + raw_value_f32 * B_F2 + C_F2
-		})
+		Ok(self.calibration.distance(raw_value))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn close(actual: f64, expected: f64, tolerance: f64) -> bool {
+		(actual - expected).abs() <= tolerance
+	}
+
+	#[test]
+	fn fit_recovers_a_line() {
+		// d = -0.05 * raw + 60 between 100 and 700 counts
+		let points: Vec<(f32, f32)> = (0..13)
+			.map(|step| {
+				let raw = 100.0 + step as f32 * 50.0;
+				(raw, -0.05 * raw + 60.0)
+			})
+			.collect();
+
+		match Calibration::fit(&points, 1) {
+			Some(Calibration::Polynomial {
+				coefficients,
+				min,
+				max,
+			}) => {
+				assert_eq!((min, max), (100.0, 700.0));
+
+				// raw = 300 * x + 400 for x scaled to -1 to 1
+				assert_eq!(coefficients.len(), 2);
+				assert!(close(coefficients[0], -15.0, 1e-4));
+				assert!(close(coefficients[1], 40.0, 1e-4));
+			}
+			calibration => panic!("unexpected fit {:?}", calibration),
+		}
+	}
+
+	#[test]
+	fn fit_recovers_a_quadratic() {
+		let curve = |raw: f32| 0.0005 * raw * raw - 0.4 * raw + 100.0;
+		let points: Vec<(f32, f32)> = (0..25)
+			.map(|step| {
+				let raw = 100.0 + step as f32 * 25.0;
+				(raw, curve(raw))
+			})
+			.collect();
+
+		let calibration = Calibration::fit(&points, 2).unwrap();
+
+		for raw in (100..701).filter(|raw| raw % 7 == 0) {
+			let distance = calibration.distance(raw);
+			let expected = curve(raw as f32);
+
+			assert!(
+				close(distance as f64, expected as f64, 1e-3),
+				"{} counts: {} instead of {}",
+				raw,
+				distance,
+				expected
+			);
+		}
+
+		// Clamped to the fitted range
+		assert_eq!(calibration.distance(0), calibration.distance(100));
+		assert_eq!(calibration.distance(1023), calibration.distance(700));
+	}
+
+	#[test]
+	fn fit_needs_enough_distinct_points() {
+		let points = [(100.0, 50.0), (200.0, 30.0), (300.0, 20.0)];
+
+		assert_eq!(Calibration::fit(&points, 3), None);
+		assert!(Calibration::fit(&points, 2).is_some());
+
+		// All at the same raw value
+		let points = [(200.0, 30.0), (200.0, 31.0), (200.0, 29.0)];
+
+		assert_eq!(Calibration::fit(&points, 1), None);
+	}
+
+	#[test]
+	fn table_interpolates_between_points() {
+		let calibration =
+			Calibration::table(&[(500.0, 10.0), (100.0, 70.0), (300.0, 20.0)])
+				.unwrap();
+
+		assert_eq!(
+			calibration,
+			Calibration::Table(vec![
+				(100.0, 70.0),
+				(300.0, 20.0),
+				(500.0, 10.0),
+			])
+		);
+
+		assert_eq!(calibration.distance(100), 70.0);
+		assert_eq!(calibration.distance(200), 45.0);
+		assert_eq!(calibration.distance(300), 20.0);
+		assert_eq!(calibration.distance(450), 12.5);
+
+		// Clamped to the outermost points
+		assert_eq!(calibration.distance(0), 70.0);
+		assert_eq!(calibration.distance(1023), 10.0);
+	}
+
+	#[test]
+	fn table_needs_two_points() {
+		assert_eq!(Calibration::table(&[]), None);
+		assert_eq!(Calibration::table(&[(100.0, 70.0)]), None);
 	}
 }
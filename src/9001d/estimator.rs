@@ -0,0 +1,253 @@
+use shared::units::GRAVITY;
+
+/// Noise levels of the altitude and vertical velocity estimator
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct EstimatorSettings {
+    // Standard deviation of the altitude readings in cm
+    pub altitude_noise: f64,
+    // Standard deviation of the acceleration readings in m/s², also covers
+    // changes of the acceleration between two readings
+    pub acceleration_noise: f64,
+}
+
+impl Default for EstimatorSettings {
+    fn default() -> EstimatorSettings {
+        EstimatorSettings {
+            altitude_noise: 1.0,
+            acceleration_noise: 0.5,
+        }
+    }
+}
+
+/// Altitude in cm and vertical velocity in cm/s, upwards is positive
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Estimate {
+    // Microseconds since the UNIX epoch
+    pub timestamp: u64,
+    pub altitude: f64,
+    pub velocity: f64,
+    // Standard deviations
+    pub altitude_uncertainty: f64,
+    pub velocity_uncertainty: f64,
+}
+
+/// Kalman filter with altitude and vertical velocity as state. The
+/// accelerometer drives the prediction and the altitude sensor corrects it,
+/// the time steps are taken from the sample timestamps.
+pub struct Estimator {
+    settings: EstimatorSettings,
+    // Unset until the first altitude reading
+    state: Option<[f64; 2]>,
+    covariance: [[f64; 2]; 2],
+    // Vertical acceleration in cm/s², gravity removed
+    acceleration: f64,
+    timestamp: u64,
+}
+
+impl Estimator {
+    pub fn new(settings: EstimatorSettings) -> Estimator {
+        Estimator {
+            settings: settings,
+            state: None,
+            covariance: [[0.0; 2]; 2],
+            acceleration: 0.0,
+            timestamp: 0,
+        }
+    }
+
+    // Readings older than the state are applied at the time of the state
+    fn predict(&mut self, timestamp: u64) {
+        if timestamp <= self.timestamp {
+            return;
+        }
+
+        let dt = (timestamp - self.timestamp) as f64 * 1e-6;
+        self.timestamp = timestamp;
+
+        let state = match self.state {
+            Some(ref mut state) => state,
+            None => return,
+        };
+
+        let a = self.acceleration;
+
+        state[0] += state[1] * dt + 0.5 * a * dt * dt;
+        state[1] += a * dt;
+
+        // P = F P Fᵀ + Q, F = [[1, dt], [0, 1]]
+        let p = self.covariance;
+        let p00 = p[0][0] + dt * (p[1][0] + p[0][1]) + dt * dt * p[1][1];
+        let p01 = p[0][1] + dt * p[1][1];
+        let p11 = p[1][1];
+
+        // Acceleration noise integrated over the step
+        let q = (self.settings.acceleration_noise * 100.0).powi(2);
+
+        self.covariance = [
+            [p00 + q * dt.powi(4) / 4.0, p01 + q * dt.powi(3) / 2.0],
+            [p01 + q * dt.powi(3) / 2.0, p11 + q * dt * dt],
+        ];
+    }
+
+    /// Reading of the accelerometer z axis in m/s², including gravity
+    pub fn acceleration(&mut self, timestamp: u64, acceleration: f64) {
+        self.predict(timestamp);
+        self.acceleration = (acceleration - GRAVITY) * 100.0;
+    }
+
+    /// Reading of the altitude sensor in cm
+    pub fn altitude(&mut self, timestamp: u64, altitude: f64) {
+        let r = self.settings.altitude_noise.powi(2);
+
+        if self.state.is_none() {
+            self.state = Some([altitude, 0.0]);
+            // The velocity is unknown until the second reading
+            self.covariance = [[r, 0.0], [0.0, 1e6]];
+            self.timestamp = timestamp;
+            return;
+        }
+
+        self.predict(timestamp);
+
+        let p = self.covariance;
+        let state = self.state.as_mut().expect("state initialized");
+
+        // H = [1, 0]
+        let innovation = altitude - state[0];
+        let s = p[0][0] + r;
+        let gain = [p[0][0] / s, p[1][0] / s];
+
+        state[0] += gain[0] * innovation;
+        state[1] += gain[1] * innovation;
+
+        self.covariance = [
+            [(1.0 - gain[0]) * p[0][0], (1.0 - gain[0]) * p[0][1]],
+            [p[1][0] - gain[1] * p[0][0], p[1][1] - gain[1] * p[0][1]],
+        ];
+    }
+
+    /// Forgets the state and the last acceleration, the next altitude
+    /// reading starts over instead of integrating across a gap in the
+    /// readings
+    pub fn reset(&mut self) {
+        self.state = None;
+        self.covariance = [[0.0; 2]; 2];
+        self.acceleration = 0.0;
+    }
+
+    /// Latest estimate, `None` before the first altitude reading
+    pub fn estimate(&self) -> Option<Estimate> {
+        self.state.map(|state| Estimate {
+            timestamp: self.timestamp,
+            altitude: state[0],
+            velocity: state[1],
+            altitude_uncertainty: self.covariance[0][0].max(0.0).sqrt(),
+            velocity_uncertainty: self.covariance[1][1].max(0.0).sqrt(),
+        })
+    }
+
+    /// Estimate extrapolated to `timestamp` with the last acceleration, the
+    /// uncertainties are those of the latest estimate
+    pub fn predicted(&self, timestamp: u64) -> Option<Estimate> {
+        let a = self.acceleration;
+
+        self.estimate().map(|estimate| {
+            let dt =
+                timestamp.saturating_sub(estimate.timestamp) as f64 * 1e-6;
+
+            Estimate {
+                timestamp: timestamp.max(estimate.timestamp),
+                altitude: estimate.altitude
+                    + estimate.velocity * dt
+                    + 0.5 * a * dt * dt,
+                velocity: estimate.velocity + a * dt,
+                ..estimate
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Readings of both sensors every 10 ms
+    const STEP_MICROS: u64 = 10_000;
+
+    // Deterministic altitude noise within ±1 cm
+    fn noise(step: u64) -> f64 {
+        ((step * 7919) % 201) as f64 / 100.0 - 1.0
+    }
+
+    #[test]
+    fn converges_on_a_constant_acceleration_fall() {
+        let mut estimator = Estimator::new(EstimatorSettings::default());
+
+        // Valves partially braking the fall, the estimator starts mid-fall
+        // without knowing the velocity
+        let reading = 5.0;
+        let acceleration = (reading - GRAVITY) * 100.0;
+        let (height, velocity) = (120.0, -50.0);
+
+        let seconds = |timestamp: u64| timestamp as f64 * 1e-6;
+        let altitude_at = |timestamp: u64| {
+            let time = seconds(timestamp);
+            height + velocity * time + 0.5 * acceleration * time * time
+        };
+
+        assert!(estimator.estimate().is_none());
+
+        for step in 0..40 {
+            let timestamp = step * STEP_MICROS;
+
+            estimator.acceleration(timestamp, reading);
+            estimator.altitude(timestamp, altitude_at(timestamp) + noise(step));
+        }
+
+        let estimate = estimator.estimate().unwrap();
+        let expected_velocity =
+            velocity + acceleration * seconds(estimate.timestamp);
+
+        assert!(
+            (estimate.altitude - altitude_at(estimate.timestamp)).abs() < 1.0,
+            "altitude {} instead of {}",
+            estimate.altitude,
+            altitude_at(estimate.timestamp)
+        );
+        assert!(
+            (estimate.velocity - expected_velocity).abs() < 5.0,
+            "velocity {} instead of {}",
+            estimate.velocity,
+            expected_velocity
+        );
+        assert!(estimate.altitude_uncertainty < 1.0);
+        assert!(estimate.velocity_uncertainty < 10.0);
+
+        // Extrapolated along the same parabola
+        let later = estimate.timestamp + 5 * STEP_MICROS;
+        let predicted = estimator.predicted(later).unwrap();
+
+        assert_eq!(predicted.timestamp, later);
+        assert!((predicted.altitude - altitude_at(later)).abs() < 2.0);
+    }
+
+    #[test]
+    fn reset_starts_over_at_the_next_altitude() {
+        let mut estimator = Estimator::new(EstimatorSettings::default());
+
+        estimator.acceleration(0, 0.0);
+        estimator.altitude(0, 80.0);
+        estimator.altitude(STEP_MICROS, 79.0);
+
+        estimator.reset();
+        assert!(estimator.estimate().is_none());
+
+        estimator.altitude(2 * STEP_MICROS, 10.0);
+
+        let estimate = estimator.estimate().unwrap();
+
+        assert_eq!(estimate.altitude, 10.0);
+        assert_eq!(estimate.velocity, 0.0);
+    }
+}
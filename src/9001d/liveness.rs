@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use shared::registry::Registry;
+use shared::types::{ChannelId, Heartbeat};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum Status {
+    #[serde(rename = "alive")]
+    Alive,
+    #[serde(rename = "stale")]
+    Stale,
+    // Also reported if nothing was ever received
+    #[serde(rename = "dead")]
+    Dead,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublisherStatus {
+    pub address: String,
+    pub status: Status,
+    // Milliseconds since the last valid frame
+    pub age: Option<u64>,
+    pub heartbeat: Option<Heartbeat>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChannelStatus {
+    pub channel: ChannelId,
+    pub status: Status,
+    // Milliseconds since the last sample
+    pub age: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LivenessReport {
+    pub publishers: Vec<PublisherStatus>,
+    pub channels: Vec<ChannelStatus>,
+}
+
+/// Last-seen times of publishers and channels
+pub struct Liveness {
+    stale_after: Duration,
+    dead_after: Duration,
+    // Keyed by publisher address, includes publishers never heard from
+    publishers: HashMap<String, Option<Instant>>,
+    heartbeats: HashMap<String, Heartbeat>,
+    channels: HashMap<ChannelId, Instant>,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+impl Liveness {
+    pub fn new(
+        addresses: &[String],
+        stale_after: Duration,
+        dead_after: Duration,
+    ) -> Liveness {
+        Liveness {
+            stale_after: stale_after,
+            dead_after: dead_after,
+            publishers: addresses
+                .iter()
+                .map(|address| (address.clone(), None))
+                .collect(),
+            heartbeats: HashMap::new(),
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn publisher_seen(&mut self, address: &str) {
+        self.publishers
+            .insert(address.to_string(), Some(Instant::now()));
+    }
+
+    pub fn heartbeat(&mut self, address: &str, heartbeat: Heartbeat) {
+        self.heartbeats.insert(address.to_string(), heartbeat);
+    }
+
+    pub fn channel_seen(&mut self, channel: &ChannelId) {
+        self.channels.insert(channel.clone(), Instant::now());
+    }
+
+    fn status(&self, last_seen: Option<Instant>) -> (Status, Option<u64>) {
+        match last_seen {
+            Some(last_seen) => {
+                let age = last_seen.elapsed();
+
+                let status = if age >= self.dead_after {
+                    Status::Dead
+                } else if age >= self.stale_after {
+                    Status::Stale
+                } else {
+                    Status::Alive
+                };
+
+                (status, Some(millis(age)))
+            }
+            None => (Status::Dead, None),
+        }
+    }
+
+    /// Status of all configured publishers and registered channels
+    pub fn report(&self, registry: &Registry) -> LivenessReport {
+        let mut publishers: Vec<PublisherStatus> = self.publishers
+            .iter()
+            .map(|(address, last_seen)| {
+                let (status, age) = self.status(*last_seen);
+
+                PublisherStatus {
+                    address: address.clone(),
+                    status: status,
+                    age: age,
+                    heartbeat: self.heartbeats.get(address).cloned(),
+                }
+            })
+            .collect();
+
+        publishers.sort_by(|a, b| a.address.cmp(&b.address));
+
+        let channels = registry
+            .channels()
+            .iter()
+            .map(|channel| {
+                let id = channel.id();
+                let (status, age) =
+                    self.status(self.channels.get(&id).cloned());
+
+                ChannelStatus {
+                    channel: id,
+                    status: status,
+                    age: age,
+                }
+            })
+            .collect();
+
+        LivenessReport {
+            publishers: publishers,
+            channels: channels,
+        }
+    }
+}
@@ -4,16 +4,29 @@ extern crate iron;
 extern crate mount;
 extern crate staticfile;
 
+extern crate libc;
 extern crate nanomsg;
 
 extern crate rppal;
 
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 
 extern crate colored;
 
+#[macro_use]
+extern crate structopt;
+
 extern crate shared;
 
+mod estimator;
+mod liveness;
+mod reload;
+mod settings;
+mod stats;
+
 use std::path::Path;
 
 use std::time::{Duration, Instant};
@@ -22,11 +35,13 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::vec::Vec;
 
 use std::io::Read;
+use std::process;
 
+use iron::method::Method;
 use iron::prelude::*;
 use iron::status;
 use mount::Mount;
@@ -36,51 +51,262 @@ use rppal::gpio::{Gpio, Level, Mode};
 
 use colored::*;
 
+use structopt::StructOpt;
+
 use nanomsg::{Protocol, Socket};
 use nanomsg::Error as NanomsgError;
 
-use shared::types::{deserialize, PubMessage, PubType, PUB_TYPES};
-use shared::utils::get_config;
+use shared::codec::CodecKind;
+use shared::filter::{ChannelFilter, ChannelFilters};
+use shared::registry::{Channel, Registry};
+use shared::types::{deserialize, deserialize_legacy, frame_codec, serialize,
+                    Body, ChannelAnnouncement, ChannelId, Envelope,
+                    FrameError, Payload, PubMessage, ReplayGuard,
+                    ACCELEROMETER_Z, IS_FALLING, LONG_DISTANCE_SENSOR};
+use shared::ticker::{OverrunPolicy, Ticker, TickerStats};
+use shared::settings::Settings;
+use shared::utils::timestamp_micros;
+
+use estimator::{Estimate, Estimator, EstimatorSettings};
+use liveness::Liveness;
+use reload::Reloader;
+use settings::{requires_authentication, ControllerParams, HubSettings};
+use stats::FrameStats;
 
 const NANOSEC_TO_MILLISEC: u32 = 1000000;
 
-fn publisher_types(_req: &mut Request) -> IronResult<Response> {
-    Ok(match serde_json::to_string(&PUB_TYPES) {
+// Period of the control loop
+const CONTROL_LOOP_PERIOD_MS: u64 = 10;
+
+// Keeps a failing subscriber socket from spinning
+const READ_ERROR_BACKOFF_MS: u64 = 100;
+
+fn publisher_types(registry: &Mutex<Registry>) -> IronResult<Response> {
+    let registry = registry.lock().unwrap();
+
+    Ok(match serde_json::to_string(registry.channels()) {
         Ok(pub_types) => Response::with((status::Ok, pub_types)),
         Err(_) => Response::with(status::InternalServerError),
     })
 }
 
-type MessageCache = HashMap<PubType, (i16, f32)>;
+fn channel_schema(registry: &Mutex<Registry>) -> IronResult<Response> {
+    let schema = registry.lock().unwrap().schema();
+
+    Ok(match serde_json::to_string(&schema) {
+        Ok(schema) => Response::with((status::Ok, schema)),
+        Err(_) => Response::with(status::InternalServerError),
+    })
+}
+
+fn liveness(
+    liveness: &Mutex<Liveness>,
+    registry: &Mutex<Registry>,
+) -> IronResult<Response> {
+    let registry = registry.lock().unwrap();
+    let report = liveness.lock().unwrap().report(&registry);
+
+    Ok(match serde_json::to_string(&report) {
+        Ok(report) => Response::with((status::Ok, report)),
+        Err(_) => Response::with(status::InternalServerError),
+    })
+}
+
+fn control_loop_stats(stats: &Mutex<TickerStats>) -> IronResult<Response> {
+    let stats = stats.lock().unwrap().clone();
+
+    Ok(match serde_json::to_string(&stats) {
+        Ok(stats) => Response::with((status::Ok, stats)),
+        Err(_) => Response::with(status::InternalServerError),
+    })
+}
+
+// Responds with the queued parameters, the control loop applies them in its
+// next iteration
+fn reload_params(
+    req: &mut Request,
+    reloader: &Mutex<Reloader>,
+) -> IronResult<Response> {
+    if req.method != Method::Post {
+        return Ok(Response::with(status::MethodNotAllowed));
+    }
+
+    Ok(match reload::reload(reloader, "HTTP") {
+        Ok(params) => match serde_json::to_string(&params) {
+            Ok(params) => Response::with((status::Ok, params)),
+            Err(_) => Response::with(status::InternalServerError),
+        },
+        Err(err) => Response::with((status::Conflict, err)),
+    })
+}
+
+// The estimate is extrapolated to the time of the request, `null` before the
+// first altitude reading
+fn estimate(cache: &Mutex<WrappedMessageCache>) -> IronResult<Response> {
+    let estimate = cache.lock().unwrap().estimate(timestamp_micros());
+
+    Ok(match serde_json::to_string(&estimate) {
+        Ok(estimate) => Response::with((status::Ok, estimate)),
+        Err(_) => Response::with(status::InternalServerError),
+    })
+}
+
+fn frame_stats(stats: &Mutex<FrameStats>) -> IronResult<Response> {
+    let stats = stats.lock().unwrap().clone();
+
+    Ok(match serde_json::to_string(&stats) {
+        Ok(stats) => Response::with((status::Ok, stats)),
+        Err(_) => Response::with(status::InternalServerError),
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Validity {
+    NeverReceived,
+    Fresh,
+    // Older than the maximum age of the channel
+    Stale,
+}
+
+/// Latest reading of a channel
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    payload: Option<Payload>,
+    received: Option<Instant>,
+    max_age: Duration,
+}
+
+impl CacheEntry {
+    fn new(max_age: Duration) -> CacheEntry {
+        CacheEntry {
+            payload: None,
+            received: None,
+            max_age: max_age,
+        }
+    }
+
+    fn validity(&self) -> Validity {
+        match self.received {
+            Some(received) if received.elapsed() > self.max_age => {
+                Validity::Stale
+            }
+            Some(_) => Validity::Fresh,
+            None => Validity::NeverReceived,
+        }
+    }
+}
+
+type MessageCache = HashMap<ChannelId, CacheEntry>;
 
 struct WrappedMessageCache {
     message_cache: MessageCache,
+    // Applies to channels without a declared maximum age
+    default_max_age: Duration,
+    // Declared with the channels
+    filters: ChannelFilters,
+    // Fed with the filtered altitude and vertical acceleration
+    estimator: Estimator,
 }
 
 // Populates message cache
 impl WrappedMessageCache {
-    fn new() -> WrappedMessageCache {
+    fn new(
+        registry: &Registry,
+        default_max_age: Duration,
+        estimator: EstimatorSettings,
+    ) -> WrappedMessageCache {
         let mut message_cache = HashMap::new();
+        let mut filters = Vec::new();
+
+        // Pre-populate message cache with all declared channels
+        for channel in registry.channels() {
+            if let Some(ref filter) = channel.filter {
+                filters.push(ChannelFilter {
+                    channel: channel.name.clone(),
+                    instance: Some(channel.instance),
+                    filter: filter.clone(),
+                });
+            }
+
+            let max_age = channel
+                .max_age_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_max_age);
 
-        // Pre-populate message cache with all possible message types
-        for pub_type in PUB_TYPES {
-            message_cache.insert(pub_type.clone(), (0, 0.0));
+            message_cache.insert(channel.id(), CacheEntry::new(max_age));
         }
 
         WrappedMessageCache {
             message_cache: message_cache,
+            default_max_age: default_max_age,
+            filters: ChannelFilters::new(filters),
+            estimator: Estimator::new(estimator),
         }
     }
 
+    // `timestamp` is the acquisition time of the reading in microseconds
+    fn update(
+        &mut self,
+        channel: ChannelId,
+        payload: Payload,
+        timestamp: u64,
+    ) {
+        let default_max_age = self.default_max_age;
+        let payload = self.filters.apply(&channel, payload);
+
+        if channel.instance == 0 {
+            match (channel.name.as_str(), payload.as_float()) {
+                (LONG_DISTANCE_SENSOR, Some(altitude)) => {
+                    self.estimator.altitude(timestamp, altitude)
+                }
+                (ACCELEROMETER_Z, Some(acceleration)) => {
+                    self.estimator.acceleration(timestamp, acceleration)
+                }
+                _ => {}
+            }
+        }
+
+        let entry = self.message_cache
+            .entry(channel)
+            .or_insert_with(|| CacheEntry::new(default_max_age));
+
+        entry.payload = Some(payload);
+        entry.received = Some(Instant::now());
+    }
+
     fn clone_inner(&mut self) -> MessageCache {
         self.message_cache.clone()
     }
+
+    fn estimate(&self, now: u64) -> Option<Estimate> {
+        self.estimator.predicted(now)
+    }
+
+    fn reset_estimator(&mut self) {
+        self.estimator.reset();
+    }
+}
+
+fn validity(msg_cache: &MessageCache, channel: &ChannelId) -> Validity {
+    msg_cache
+        .get(channel)
+        .map(|entry| entry.validity())
+        .unwrap_or(Validity::NeverReceived)
+}
+
+// Scalar reading of a channel, missing and compound payloads read as zero
+fn scalar(msg_cache: &MessageCache, channel: &ChannelId) -> f32 {
+    msg_cache
+        .get(channel)
+        .and_then(|entry| entry.payload.as_ref())
+        .and_then(|payload| payload.as_float())
+        .unwrap_or(0.0) as f32
 }
 
 struct Pigeon {
-    exp_deceleration: f32,
-    tolerance: f32,
-    target_altitude: f32,
+    // Replaced as a whole on reload
+    params: ControllerParams,
+    overrun_policy: OverrunPolicy,
 }
 
 fn halt_altitude(altitude: f32, velocity: f32, deceleration: f32) -> f32 {
@@ -92,26 +318,26 @@ fn is_above(alt_above: f32, alt_below: f32, tolerance: f32) -> bool {
 }
 
 impl Pigeon {
-    fn new(
-        exp_deceleration: f32,
-        tolerance: f32,
-        target_altitude: f32,
-    ) -> Pigeon {
+    fn new(params: ControllerParams, overrun_policy: OverrunPolicy) -> Pigeon {
         Pigeon {
-            exp_deceleration: exp_deceleration,
-            tolerance: tolerance,
-            target_altitude: target_altitude,
+            params: params,
+            overrun_policy: overrun_policy,
         }
     }
 
     fn control_loop(
         &mut self,
         wrapped_message_cache_arc: Arc<Mutex<WrappedMessageCache>>,
+        loop_stats_arc: Arc<Mutex<TickerStats>>,
+        reloader_arc: Arc<Mutex<Reloader>>,
     ) {
         let mut msg_cache: MessageCache;
+        let mut estimate: Option<Estimate>;
 
-        let max_loop_duration = Duration::from_millis(10);
-        let mut loop_time: Instant;
+        let mut ticker = Ticker::new(
+            Duration::from_millis(CONTROL_LOOP_PERIOD_MS),
+            self.overrun_policy,
+        );
 
         let mut fall_time = Instant::now();
         let mut fall_time_set = false;
@@ -150,34 +376,122 @@ impl Pigeon {
 
         let mut is_boosting = false;
 
-        msg_cache = wrapped_message_cache_arc.lock().unwrap().clone_inner();
+        let altitude_channel = ChannelId::new(LONG_DISTANCE_SENSOR, 0);
+        let acceleration_channel = ChannelId::new(ACCELEROMETER_Z, 0);
+        let is_falling_channel = ChannelId::new(IS_FALLING, 0);
 
-        let mut curr_velocity = 0.0;
+        // Valves are closed while any of these is not fresh, the estimator
+        // would otherwise keep integrating a stale acceleration
+        let critical_channels = [
+            altitude_channel,
+            acceleration_channel,
+            is_falling_channel.clone(),
+        ];
+        let mut failsafe = false;
 
-        let mut last_altitude = msg_cache[&PubType::LongDistanceSensor].1;
+        loop {
+            *loop_stats_arc.lock().unwrap() = ticker.stats().clone();
 
-        thread::sleep(max_loop_duration);
+            if let Some(late) = ticker.wait() {
+                println!(
+                    "{}",
+                    format!("Maximum loop time exceeded by {:?}", late).red()
+                );
+            }
 
-        loop {
-            loop_time = Instant::now();
+            {
+                let mut wrapped = wrapped_message_cache_arc.lock().unwrap();
+
+                msg_cache = wrapped.clone_inner();
+                estimate = wrapped.estimate(timestamp_micros());
+            }
+
+            let falling = fall_time_set
+                || scalar(&msg_cache, &is_falling_channel) != 0.0;
 
-            msg_cache = wrapped_message_cache_arc.lock().unwrap().clone_inner();
+            // Applied between iterations so a pass never mixes parameters
+            let reloaded = reloader_arc.lock().unwrap().update(falling);
 
-            curr_velocity =
-                if msg_cache[&PubType::LongDistanceSensor].1 == last_altitude {
-                    curr_velocity
+            if let Some(params) = reloaded {
+                if falling {
+                    println!(
+                        "{}",
+                        "Rejected reloaded parameters, the vehicle is falling"
+                            .red()
+                    );
                 } else {
-                    // cm / s
-                    (msg_cache[&PubType::LongDistanceSensor].1 - last_altitude)
-                        * 100.0
-                };
+                    let changes = self.params.changes(&params);
+
+                    if changes.is_empty() {
+                        println!("Reloaded parameters are unchanged");
+                    }
+
+                    for change in changes {
+                        println!("{}", format!("Reloaded {}", change).green());
+                    }
+
+                    self.params = params;
+                }
+            }
+
+            let mut invalid: Vec<String> = critical_channels
+                .iter()
+                .filter(|channel| {
+                    validity(&msg_cache, channel) != Validity::Fresh
+                })
+                .map(|channel| channel.to_string())
+                .collect();
+
+            if estimate.is_none() {
+                invalid.push(String::from("the altitude estimate"));
+            }
+
+            if !invalid.is_empty() {
+                if !failsafe {
+                    println!(
+                        "{}",
+                        format!(
+                            "Entering failsafe, no fresh readings of {}",
+                            invalid.join(", ")
+                        ).red()
+                    );
+
+                    failsafe = true;
+
+                    // Starts over from the readings after the gap
+                    wrapped_message_cache_arc
+                        .lock()
+                        .unwrap()
+                        .reset_estimator();
+                }
+
+                gpio.write(20, Level::Low);
+                gpio.write(21, Level::Low);
+
+                fall_time_set = false;
+                is_boosting = false;
+
+                continue;
+            }
+
+            let estimate = estimate.expect("estimate checked above");
+
+            // cm and cm / s
+            let altitude = estimate.altitude as f32;
+            let curr_velocity = estimate.velocity as f32;
+
+            if failsafe {
+                println!("{}", "Leaving failsafe".green());
+
+                failsafe = false;
+            }
+
+            let params = self.params;
+
+            let target = params.target_altitude;
 
-            if is_above(
-                msg_cache[&PubType::LongDistanceSensor].1,
-                self.target_altitude,
-                self.tolerance + 5.0,
-            ) {
-                if msg_cache[&PubType::IsFalling].0 == 1 {
+            if is_above(altitude, target, params.tolerance + 5.0) {
+                if scalar(&msg_cache, &is_falling_channel) != 0.0 {
                     if !fall_time_set {
                         fall_time = Instant::now();
                         fall_time_set = true;
@@ -185,12 +499,12 @@ impl Pigeon {
 
                     if !is_above(
                         halt_altitude(
-                            msg_cache[&PubType::LongDistanceSensor].1,
+                            altitude,
                             curr_velocity,
-                            self.exp_deceleration,
+                            params.exp_deceleration,
                         ),
-                        self.target_altitude,
-                        self.tolerance + 2.0,
+                        target,
+                        params.tolerance + 2.0,
                     ) {
                         gpio.write(20, Level::High);
                         gpio.write(21, Level::High);
@@ -208,22 +522,16 @@ impl Pigeon {
 
             if fall_time_set {
                 println!(
-                    "is_boosting: {} altitude: {:>8} vel: {} \
-                     current_fall_duration: {}",
+                    "is_boosting: {} altitude: {:>8.1} ± {:.1} \
+                     vel: {:.1} ± {:.1} current_fall_duration: {}",
                     is_boosting as u8,
-                    msg_cache[&PubType::LongDistanceSensor].1,
+                    altitude,
+                    estimate.altitude_uncertainty,
                     curr_velocity,
+                    estimate.velocity_uncertainty,
                     fall_time.elapsed().subsec_nanos() / NANOSEC_TO_MILLISEC,
                 );
             }
-
-            last_altitude = msg_cache[&PubType::LongDistanceSensor].1;
-
-            if loop_time.elapsed() > max_loop_duration {
-                println!("{}", "Maximum loop time exceeded".red());
-            } else {
-                thread::sleep(max_loop_duration - loop_time.elapsed());
-            }
         }
     }
 
@@ -257,102 +565,470 @@ impl Pigeon {
     }
 }
 
+/// Subscribes to a single publisher so frames can be attributed to it
 struct Consumer {
+    publisher: String,
+    // Pre-shared key of the publisher
+    key: Option<Vec<u8>>,
+    require_auth: bool,
     sub_socket: Socket,
-    pub_socket: Socket,
+    pub_socket: Arc<Mutex<Socket>>,
+    // Codec mission control expects on the forwarding socket
+    forward_codec: CodecKind,
+    // Last sequence number seen per publisher id
+    sequences: HashMap<String, u64>,
+    // Rejects signed frames that are not newer than the accepted ones
+    replay_guard: ReplayGuard,
+    // Set while frames cannot be re-encoded, reported only once
+    forward_failing: bool,
+    registry: Arc<Mutex<Registry>>,
+    // Channels that have already been reported as unregistered
+    unknown_channels: HashSet<ChannelId>,
+    stats: Arc<Mutex<FrameStats>>,
+    liveness: Arc<Mutex<Liveness>>,
 }
 
 impl Consumer {
     fn new(
-        publishers: Vec<String>,
-        pub_address: &str,
+        publisher: String,
+        key: Option<&str>,
+        pub_socket: Arc<Mutex<Socket>>,
+        forward_codec: CodecKind,
+        registry: Arc<Mutex<Registry>>,
+        stats: Arc<Mutex<FrameStats>>,
+        liveness: Arc<Mutex<Liveness>>,
     ) -> Result<Consumer, NanomsgError> {
         let mut sub_socket = Socket::new(Protocol::Sub)?;
 
         sub_socket.subscribe(b"").ok();
-
-        for publisher in publishers {
-            sub_socket.connect(&publisher).expect(&format!(
-                "connection to publisher failed ({})",
-                publisher
-            ));
-        }
-
-        // Used to forward messages to 9001-mission_control
-        let mut pub_socket = Socket::new(Protocol::Pub)?;
-        pub_socket.bind(pub_address)?;
+        sub_socket.connect(&publisher)?;
 
         Ok(Consumer {
+            require_auth: requires_authentication(&publisher),
+            publisher: publisher,
+            key: key.map(|key| key.as_bytes().to_vec()),
             sub_socket: sub_socket,
             pub_socket: pub_socket,
+            forward_codec: forward_codec,
+            sequences: HashMap::new(),
+            replay_guard: ReplayGuard::new(),
+            forward_failing: false,
+            registry: registry,
+            unknown_channels: HashSet::new(),
+            stats: stats,
+            liveness: liveness,
         })
     }
 
+    // Falls back to the bare message format during migration
+    fn parse(&mut self, raw_msg: &[u8]) -> Result<Envelope, FrameError> {
+        let key = self.key.as_ref().map(|key| &key[..]);
+
+        let envelope = match deserialize(raw_msg, key) {
+            Err(FrameError::NotEnveloped) => deserialize_legacy(raw_msg)
+                .map(|pub_msg| {
+                    Envelope::from_legacy(pub_msg, timestamp_micros())
+                })
+                .ok_or(FrameError::Malformed),
+            result => result,
+        }?;
+
+        // Legacy messages are never signed
+        if self.require_auth && !envelope.authenticated {
+            return Err(FrameError::Unauthenticated);
+        }
+
+        // Unsigned frames could be forged just as easily as replayed
+        if envelope.authenticated {
+            self.replay_guard.check(&envelope, timestamp_micros())?;
+        }
+
+        Ok(envelope)
+    }
+
+    fn track_sequence(&mut self, envelope: &Envelope) {
+        if envelope.is_legacy() {
+            return;
+        }
+
+        if let Some(last_sequence) = self.sequences
+            .insert(envelope.publisher_id.clone(), envelope.sequence)
+        {
+            let expected = last_sequence.wrapping_add(1);
+
+            if envelope.sequence <= last_sequence {
+                println!(
+                    "{}",
+                    format!("{} restarted", envelope.publisher_id).yellow()
+                );
+            } else if envelope.sequence != expected {
+                let lost = envelope.sequence.wrapping_sub(expected);
+
+                println!(
+                    "{}",
+                    format!(
+                        "Lost {} frame(s) from {}",
+                        lost, envelope.publisher_id
+                    ).yellow()
+                );
+
+                self.stats.lock().unwrap().lost(&self.publisher, lost);
+            }
+        }
+    }
+
+    // Non-blocking message forwarding to WebSocket clients
+    // Write failures are ignored, frames that cannot be re-encoded are
+    // counted and reported once until forwarding works again
+    fn forward(&mut self, raw_msg: &[u8], envelope: &Envelope) {
+        if frame_codec(raw_msg).ok() == Some(self.forward_codec) {
+            self.pub_socket.lock().unwrap().nb_write(raw_msg).ok();
+            return;
+        }
+
+        match serialize(envelope, self.forward_codec, None) {
+            Some(frame) => {
+                self.pub_socket.lock().unwrap().nb_write(&frame).ok();
+                self.forward_failing = false;
+            }
+            None => {
+                let err = format!(
+                    "could not encode frame from {} as {:?}",
+                    self.publisher, self.forward_codec
+                );
+
+                if !self.forward_failing {
+                    println!("{}", format!("Not forwarding ({})", err).red());
+                    self.forward_failing = true;
+                }
+
+                self.stats
+                    .lock()
+                    .unwrap()
+                    .forward_error(&self.publisher, err);
+            }
+        }
+    }
+
+    fn register(
+        &mut self,
+        publisher_id: &str,
+        announcements: &[ChannelAnnouncement],
+    ) {
+        let mut registry = self.registry.lock().unwrap();
+
+        for announcement in announcements {
+            let channel = &announcement.channel;
+
+            // Announcements are repeated periodically
+            let announced = match registry.lookup(channel) {
+                Some(&Channel {
+                    publisher: Some(ref id),
+                    ..
+                }) => id == publisher_id,
+                _ => false,
+            };
+
+            if announced {
+                continue;
+            }
+
+            if registry.register(publisher_id, announcement) {
+                println!("{} registered {}", publisher_id, channel);
+            } else {
+                println!(
+                    "{}",
+                    format!(
+                        "{} registered undeclared channel {}",
+                        publisher_id, channel
+                    ).yellow()
+                );
+            }
+        }
+    }
+
+    // Drops messages on channels that were neither declared nor announced
+    fn registered(&mut self, messages: Vec<PubMessage>) -> Vec<PubMessage> {
+        let registry = self.registry.lock().unwrap();
+        let unknown_channels = &mut self.unknown_channels;
+
+        messages
+            .into_iter()
+            .filter(|pub_msg| {
+                if registry.lookup(&pub_msg.channel).is_some() {
+                    return true;
+                }
+
+                if unknown_channels.insert(pub_msg.channel.clone()) {
+                    println!(
+                        "{}",
+                        format!(
+                            "Dropping messages on unregistered channel {}",
+                            pub_msg.channel
+                        ).red()
+                    );
+                }
+
+                false
+            })
+            .collect()
+    }
+
+    // `timestamp` is the acquisition time of the messages
+    fn cache(
+        &mut self,
+        messages: Vec<PubMessage>,
+        timestamp: u64,
+        wrapped_message_cache_arc: &Mutex<WrappedMessageCache>,
+    ) {
+        let messages = self.registered(messages);
+
+        {
+            let mut liveness = self.liveness.lock().unwrap();
+
+            for pub_msg in &messages {
+                liveness.channel_seen(&pub_msg.channel);
+            }
+        }
+
+        if let Ok(mut wrapped_message_cache) = wrapped_message_cache_arc.lock()
+        {
+            for pub_msg in messages {
+                wrapped_message_cache.update(
+                    pub_msg.channel,
+                    pub_msg.payload,
+                    timestamp,
+                );
+            }
+        }
+    }
+
     fn consume(
         &mut self,
         wrapped_message_cache_arc: Arc<Mutex<WrappedMessageCache>>,
     ) {
-        let mut raw_msg = [0u8; 16];
-        let mut pub_msg: PubMessage;
+        let mut raw_msg: Vec<u8> = Vec::new();
+        let mut envelope: Envelope;
 
         loop {
-            if let Err(err) = self.sub_socket.read(&mut raw_msg) {
-                panic!(err);
+            raw_msg.clear();
+
+            if let Err(err) = self.sub_socket.read_to_end(&mut raw_msg) {
+                println!(
+                    "{}",
+                    format!("Reading from {} failed ({})", self.publisher, err)
+                        .red()
+                );
+
+                self.stats
+                    .lock()
+                    .unwrap()
+                    .read_error(&self.publisher, err.to_string());
+
+                thread::sleep(Duration::from_millis(READ_ERROR_BACKOFF_MS));
             } else {
-                pub_msg = deserialize(&raw_msg).unwrap();
-
-                if let Ok(mut wrapped_message_cache) =
-                    wrapped_message_cache_arc.lock()
-                {
-                    wrapped_message_cache.message_cache.insert(
-                        pub_msg.pub_type,
-                        (pub_msg.integral, pub_msg.decimal),
-                    );
-                }
+                envelope = match self.parse(&raw_msg) {
+                    Ok(envelope) => envelope,
+                    Err(err) => {
+                        println!(
+                            "{}",
+                            format!(
+                                "Dropping frame from {} ({})",
+                                self.publisher, err
+                            ).red()
+                        );
+
+                        let mut stats = self.stats.lock().unwrap();
+
+                        match err {
+                            FrameError::BadSignature
+                            | FrameError::Unauthenticated
+                            | FrameError::Replayed
+                            | FrameError::OutsideWindow => {
+                                stats.unauthenticated(&self.publisher, err)
+                            }
+                            _ => stats.corrupt(&self.publisher, err),
+                        }
+                        continue;
+                    }
+                };
 
-                // Non-blocking message forwarding to WebSocket clients
-                // Failure is ignored,
-                self.pub_socket.nb_write(&raw_msg).ok();
+                self.stats
+                    .lock()
+                    .unwrap()
+                    .valid(&self.publisher, envelope.is_legacy());
+
+                self.liveness
+                    .lock()
+                    .unwrap()
+                    .publisher_seen(&self.publisher);
+
+                self.track_sequence(&envelope);
+                self.forward(&raw_msg, &envelope);
+
+                match envelope.body {
+                    Body::Announce(announcements) => {
+                        self.register(&envelope.publisher_id, &announcements);
+                    }
+                    Body::Sample(messages) => {
+                        self.cache(
+                            messages,
+                            envelope.timestamp,
+                            &wrapped_message_cache_arc,
+                        );
+                    }
+                    // Unpacked in acquisition order, the newest sample wins
+                    Body::Batch(samples) => for sample in samples {
+                        self.cache(
+                            sample.messages,
+                            sample.timestamp,
+                            &wrapped_message_cache_arc,
+                        );
+                    },
+                    Body::Heartbeat(heartbeat) => {
+                        self.liveness
+                            .lock()
+                            .unwrap()
+                            .heartbeat(&self.publisher, heartbeat);
+                    }
+                }
             }
         }
     }
 }
 
+#[derive(StructOpt, Debug)]
+#[structopt(name = "9001d")]
+struct Opt {
+    /// Configuration file, defaults to the rig config
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+
+    /// Validate the configuration and exit without starting the hardware
+    #[structopt(long = "check-config")]
+    check_config: bool,
+
+    /// Replaces the configured publishers if given, may be repeated
+    #[structopt(long = "publisher")]
+    publishers: Vec<String>,
+}
+
 fn main() {
-    let config = get_config("9001d").expect("could not create config");
+    let opt = Opt::from_args();
+
+    let config = Settings::load(opt.config.as_ref().map(String::as_str), true)
+        .map(|settings| settings.into_config())
+        .unwrap_or_else(|err| {
+            eprintln!("{}", format!("Could not load config ({})", err).red());
+            process::exit(1);
+        });
+
+    let loaded = HubSettings::load(&config, &opt.publishers);
 
-    let address = config.get_str("address").unwrap();
+    let (settings, registry) = match loaded {
+        Ok(loaded) => loaded,
+        Err(problems) => {
+            eprintln!("{}", "Invalid config:".red());
 
-    let mut publishers: Vec<String> = Vec::new();
+            for problem in problems {
+                eprintln!("  - {}", problem);
+            }
 
-    for publisher in config.get_array("publishers").unwrap_or(vec![]) {
-        if let Ok(publisher) = publisher.into_str() {
-            publishers.push(publisher);
+            process::exit(1);
         }
+    };
+
+    for warning in settings.warnings() {
+        println!("{}", warning.yellow());
+    }
+
+    if opt.check_config {
+        println!("{}", "Config is valid".green());
+        return;
     }
 
-    let wrapped_msg_cache_arc =
-        Arc::new(Mutex::new(WrappedMessageCache::new()));
+    let default_max_age = Duration::from_millis(settings.max_age_ms);
+
+    let wrapped_msg_cache_arc = Arc::new(Mutex::new(WrappedMessageCache::new(
+        &registry,
+        default_max_age,
+        settings.estimator,
+    )));
 
-    let wrapped_msg_cache_arc_consumer = wrapped_msg_cache_arc.clone();
     let wrapped_msg_cache_arc_pigeon = wrapped_msg_cache_arc.clone();
+    let wrapped_msg_cache_arc_web = wrapped_msg_cache_arc.clone();
+
+    let registry_arc = Arc::new(Mutex::new(registry));
+    let registry_arc_web = registry_arc.clone();
+    let registry_arc_schema = registry_arc.clone();
+
+    // Used to forward messages to 9001-mission_control
+    let mut pub_socket = Socket::new(Protocol::Pub).unwrap();
+    pub_socket.bind(&settings.address).unwrap();
+
+    let pub_socket_arc = Arc::new(Mutex::new(pub_socket));
+
+    let frame_stats_arc = Arc::new(Mutex::new(FrameStats::new()));
+    let frame_stats_arc_web = frame_stats_arc.clone();
+
+    let addresses: Vec<String> = settings
+        .publishers
+        .iter()
+        .map(|publisher| publisher.address().to_string())
+        .collect();
+
+    let liveness_arc = Arc::new(Mutex::new(Liveness::new(
+        &addresses,
+        Duration::from_millis(settings.stale_after_ms),
+        Duration::from_millis(settings.dead_after_ms),
+    )));
+    let liveness_arc_web = liveness_arc.clone();
+    let registry_arc_liveness = registry_arc.clone();
+
+    for publisher in &settings.publishers {
+        let address = publisher.address().to_string();
+
+        let mut consumer = Consumer::new(
+            address.clone(),
+            publisher.key(),
+            pub_socket_arc.clone(),
+            settings.forward_codec,
+            registry_arc.clone(),
+            frame_stats_arc.clone(),
+            liveness_arc.clone(),
+        ).expect(&format!("connection to publisher failed ({})", address));
+
+        let wrapped_msg_cache_arc_consumer = wrapped_msg_cache_arc.clone();
 
-    let mut consumer = Consumer::new(publishers, &address).unwrap();
+        thread::spawn(move || {
+            consumer.consume(wrapped_msg_cache_arc_consumer);
+        });
+    }
+
+    let mut pigeon = Pigeon::new(
+        settings.controller_params(),
+        settings.control_loop_overrun,
+    );
+
+    let reloader_arc = Arc::new(Mutex::new(Reloader::new(opt.config)));
+    let reloader_arc_pigeon = reloader_arc.clone();
+    let reloader_arc_watch = reloader_arc.clone();
+
+    reload::handle_hangup();
 
     thread::spawn(move || {
-        consumer.consume(wrapped_msg_cache_arc_consumer);
+        reload::watch(reloader_arc_watch);
     });
 
-    let exp_deceleration =
-        config.get_float("exp_deceleration").unwrap_or(100.0) as f32;
-    let tolerance = config.get_float("tolerance").unwrap_or(5.0) as f32;
-    let target_altitude =
-        config.get_float("target_altitude").unwrap_or(8.0) as f32;
-
-    let mut pigeon = Pigeon::new(exp_deceleration, tolerance, target_altitude);
+    let loop_stats_arc = Arc::new(Mutex::new(TickerStats::default()));
+    let loop_stats_arc_web = loop_stats_arc.clone();
 
     thread::spawn(move || {
-        pigeon.control_loop(wrapped_msg_cache_arc_pigeon);
+        pigeon.control_loop(
+            wrapped_msg_cache_arc_pigeon,
+            loop_stats_arc,
+            reloader_arc_pigeon,
+        );
     });
 
     let mut mount = Mount::new();
@@ -366,7 +1042,27 @@ fn main() {
         Static::new(Path::new("web/9001-mission_control/node_modules")),
     );
 
-    mount.mount("/publisher-types", publisher_types);
+    mount.mount("/publisher-types", move |_req: &mut Request| {
+        publisher_types(&registry_arc_web)
+    });
+    mount.mount("/channel-schema", move |_req: &mut Request| {
+        channel_schema(&registry_arc_schema)
+    });
+    mount.mount("/frame-stats", move |_req: &mut Request| {
+        frame_stats(&frame_stats_arc_web)
+    });
+    mount.mount("/liveness", move |_req: &mut Request| {
+        liveness(&liveness_arc_web, &registry_arc_liveness)
+    });
+    mount.mount("/control-loop-stats", move |_req: &mut Request| {
+        control_loop_stats(&loop_stats_arc_web)
+    });
+    mount.mount("/estimate", move |_req: &mut Request| {
+        estimate(&wrapped_msg_cache_arc_web)
+    });
+    mount.mount("/reload", move |req: &mut Request| {
+        reload_params(req, &reloader_arc)
+    });
 
     Iron::new(mount).http("0.0.0.0:3000").unwrap();
 }
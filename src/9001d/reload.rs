@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use colored::*;
+
+use libc;
+
+use shared::settings::{config_path, Settings};
+
+use settings::{ControllerParams, HubSettings};
+
+// Interval of checking the config file for changes and SIGHUP
+const WATCH_INTERVAL_MS: u64 = 250;
+
+static HANGUP: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+/// SIGHUP triggers a reload, it is handled by the watch thread
+pub fn handle_hangup() {
+    unsafe {
+        libc::signal(libc::SIGHUP, on_hangup as libc::sighandler_t);
+    }
+}
+
+/// Hands reloaded controller parameters over to the control loop, which
+/// applies them between two iterations
+pub struct Reloader {
+    // Config path given on the command line
+    config: Option<String>,
+    pending: Option<ControllerParams>,
+    // Reported by the control loop, reloads are rejected during a fall
+    falling: bool,
+}
+
+impl Reloader {
+    pub fn new(config: Option<String>) -> Reloader {
+        Reloader {
+            config: config,
+            pending: None,
+            falling: false,
+        }
+    }
+
+    fn submit(&mut self, params: ControllerParams) -> Result<(), String> {
+        if self.falling {
+            return Err(String::from("the vehicle is falling"));
+        }
+
+        self.pending = Some(params);
+
+        Ok(())
+    }
+
+    /// Records whether the vehicle is falling and takes the parameters
+    /// submitted since the last iteration
+    pub fn update(&mut self, falling: bool) -> Option<ControllerParams> {
+        self.falling = falling;
+        self.pending.take()
+    }
+}
+
+fn read_params(config: Option<&str>) -> Result<ControllerParams, String> {
+    let config = Settings::load(config, true)
+        .map_err(|err| err.to_string())?
+        .into_config();
+
+    HubSettings::load(&config, &[])
+        .map(|(settings, _)| settings.controller_params())
+        .map_err(|problems| problems.join(", "))
+}
+
+/// Re-reads the config and queues its controller parameters, other settings
+/// only take effect after a restart
+pub fn reload(
+    reloader: &Mutex<Reloader>,
+    source: &str,
+) -> Result<ControllerParams, String> {
+    let config = reloader.lock().unwrap().config.clone();
+
+    // The file is read without holding the lock the control loop waits on
+    let result = read_params(config.as_ref().map(String::as_str))
+        .and_then(|params| {
+            reloader.lock().unwrap().submit(params).map(|_| params)
+        });
+
+    match result {
+        Ok(_) => {
+            println!("{}", format!("Reload requested by {}", source).green())
+        }
+        Err(ref err) => println!(
+            "{}",
+            format!("Rejected reload requested by {} ({})", source, err).red()
+        ),
+    }
+
+    result
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reloads on SIGHUP and whenever the config file changes
+pub fn watch(reloader: Arc<Mutex<Reloader>>) {
+    let config = reloader.lock().unwrap().config.clone();
+    let path = config_path(config.as_ref().map(String::as_str));
+
+    let mut last_modified = path.as_ref().and_then(modified);
+
+    loop {
+        thread::sleep(Duration::from_millis(WATCH_INTERVAL_MS));
+
+        if HANGUP.swap(false, Ordering::SeqCst) {
+            let _ = reload(&reloader, "SIGHUP");
+        }
+
+        let current = path.as_ref().and_then(modified);
+
+        if current != last_modified {
+            last_modified = current;
+
+            let _ = reload(&reloader, "config file change");
+        }
+    }
+}
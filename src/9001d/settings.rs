@@ -0,0 +1,381 @@
+use std::collections::HashSet;
+
+use config::{Config, ConfigError};
+use serde::de::DeserializeOwned;
+
+use shared::codec::CodecKind;
+use shared::registry::Registry;
+use shared::ticker::OverrunPolicy;
+use shared::types::{ChannelId, LONG_DISTANCE_SENSOR};
+
+use estimator::EstimatorSettings;
+
+// Transports nanomsg can bind and connect to
+const SCHEMES: [&'static str; 4] = ["inproc://", "ipc://", "tcp://", "ws://"];
+
+/// Publisher addresses are given bare or together with a pre-shared key
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum PublisherConfig {
+    Address(String),
+    Keyed { address: String, key: Option<String> },
+}
+
+impl PublisherConfig {
+    pub fn address(&self) -> &str {
+        match *self {
+            PublisherConfig::Address(ref address) => address,
+            PublisherConfig::Keyed { ref address, .. } => address,
+        }
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        match *self {
+            PublisherConfig::Keyed {
+                key: Some(ref key), ..
+            } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+// Only local transports are trusted without authentication
+pub fn requires_authentication(address: &str) -> bool {
+    !(address.starts_with("ipc://") || address.starts_with("inproc://"))
+}
+
+fn default_exp_deceleration() -> f32 {
+    100.0
+}
+
+fn default_tolerance() -> f32 {
+    5.0
+}
+
+fn default_target_altitude() -> f32 {
+    8.0
+}
+
+fn default_stale_after_ms() -> u64 {
+    1000
+}
+
+fn default_dead_after_ms() -> u64 {
+    3000
+}
+
+fn default_max_age_ms() -> u64 {
+    100
+}
+
+/// Controller parameters, these can be reloaded at runtime
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct ControllerParams {
+    pub exp_deceleration: f32,
+    pub tolerance: f32,
+    pub target_altitude: f32,
+}
+
+impl ControllerParams {
+    /// Human readable list of the parameters that differ in `other`
+    pub fn changes(&self, other: &ControllerParams) -> Vec<String> {
+        [
+            ("exp_deceleration", self.exp_deceleration, other.exp_deceleration),
+            ("tolerance", self.tolerance, other.tolerance),
+            ("target_altitude", self.target_altitude, other.target_altitude),
+        ].iter()
+            .filter(|&&(_, old, new)| old != new)
+            .map(|&(name, old, new)| format!("{} {} -> {}", name, old, new))
+            .collect()
+    }
+}
+
+/// Top level of the rig config
+#[derive(Clone, Debug)]
+pub struct HubSettings {
+    // Mission control subscribes here
+    pub address: String,
+    pub forward_codec: CodecKind,
+    pub publishers: Vec<PublisherConfig>,
+    pub exp_deceleration: f32,
+    pub tolerance: f32,
+    pub target_altitude: f32,
+    pub control_loop_overrun: OverrunPolicy,
+    pub stale_after_ms: u64,
+    pub dead_after_ms: u64,
+    pub max_age_ms: u64,
+    pub estimator: EstimatorSettings,
+}
+
+// Reads a single key, so a value of the wrong type is reported together
+// with the other problems. Missing keys and values that don't parse take
+// `default`.
+fn field<T>(
+    config: &Config,
+    key: &str,
+    default: T,
+    problems: &mut Vec<String>,
+) -> T
+where
+    T: DeserializeOwned,
+{
+    match config.get::<T>(key) {
+        Ok(value) => value,
+        Err(ConfigError::NotFound(_)) => default,
+        Err(err) => {
+            problems.push(err.to_string());
+            default
+        }
+    }
+}
+
+fn check_address(name: &str, address: &str, problems: &mut Vec<String>) {
+    if !SCHEMES.iter().any(|scheme| address.starts_with(scheme)) {
+        problems.push(format!(
+            "{} '{}' must start with one of {}",
+            name,
+            address,
+            SCHEMES.join(", ")
+        ));
+    }
+}
+
+impl HubSettings {
+    pub fn controller_params(&self) -> ControllerParams {
+        ControllerParams {
+            exp_deceleration: self.exp_deceleration,
+            tolerance: self.tolerance,
+            target_altitude: self.target_altitude,
+        }
+    }
+
+    /// Reads and validates the hub settings and channels, returns every
+    /// problem found instead of stopping at the first one. Non-empty
+    /// `publishers` replace the configured ones, keys of configured
+    /// publishers with the same address are kept.
+    pub fn load(
+        config: &Config,
+        publishers: &[String],
+    ) -> Result<(HubSettings, Registry), Vec<String>> {
+        let mut problems = Vec::new();
+
+        let registry = match Registry::from_config(config) {
+            Ok(registry) => Some(registry),
+            Err(err) => {
+                problems.push(format!("invalid channels ({})", err));
+                None
+            }
+        };
+
+        let mut settings = HubSettings {
+            address: field(config, "address", String::new(), &mut problems),
+            forward_codec: field(
+                config,
+                "forward_codec",
+                CodecKind::default(),
+                &mut problems,
+            ),
+            publishers: field(config, "publishers", vec![], &mut problems),
+            exp_deceleration: field(
+                config,
+                "exp_deceleration",
+                default_exp_deceleration(),
+                &mut problems,
+            ),
+            tolerance: field(
+                config,
+                "tolerance",
+                default_tolerance(),
+                &mut problems,
+            ),
+            target_altitude: field(
+                config,
+                "target_altitude",
+                default_target_altitude(),
+                &mut problems,
+            ),
+            control_loop_overrun: field(
+                config,
+                "control_loop_overrun",
+                OverrunPolicy::default(),
+                &mut problems,
+            ),
+            stale_after_ms: field(
+                config,
+                "stale_after_ms",
+                default_stale_after_ms(),
+                &mut problems,
+            ),
+            dead_after_ms: field(
+                config,
+                "dead_after_ms",
+                default_dead_after_ms(),
+                &mut problems,
+            ),
+            max_age_ms: field(
+                config,
+                "max_age_ms",
+                default_max_age_ms(),
+                &mut problems,
+            ),
+            estimator: field(
+                config,
+                "estimator",
+                EstimatorSettings::default(),
+                &mut problems,
+            ),
+        };
+
+        if !publishers.is_empty() {
+            settings.publishers = publishers
+                .iter()
+                .map(|address| settings.publisher(address))
+                .collect();
+        }
+
+        problems.extend(settings.validate(registry.as_ref()));
+
+        match registry {
+            Some(registry) if problems.is_empty() => Ok((settings, registry)),
+            _ => Err(problems),
+        }
+    }
+
+    fn publisher(&self, address: &str) -> PublisherConfig {
+        self.publishers
+            .iter()
+            .find(|publisher| publisher.address() == address)
+            .cloned()
+            .unwrap_or_else(|| PublisherConfig::Address(address.to_string()))
+    }
+
+    /// Values that parse but would make the hub misbehave
+    pub fn validate(&self, registry: Option<&Registry>) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.address.is_empty() {
+            problems.push(String::from("address is missing"));
+        } else {
+            check_address("address", &self.address, &mut problems);
+        }
+
+        let mut addresses = HashSet::new();
+
+        for publisher in &self.publishers {
+            let address = publisher.address();
+
+            check_address("publisher", address, &mut problems);
+
+            if !addresses.insert(address) {
+                problems
+                    .push(format!("publisher '{}' is listed twice", address));
+            }
+
+            if publisher.key() == Some("") {
+                problems
+                    .push(format!("key of publisher '{}' is empty", address));
+            }
+        }
+
+        if !(self.exp_deceleration > 0.0 && self.exp_deceleration.is_finite())
+        {
+            problems.push(format!(
+                "exp_deceleration must be positive, got {}",
+                self.exp_deceleration
+            ));
+        }
+
+        if !(self.target_altitude > 0.0 && self.target_altitude.is_finite()) {
+            problems.push(format!(
+                "target_altitude must be positive, got {}",
+                self.target_altitude
+            ));
+        }
+
+        if !(self.tolerance >= 0.0 && self.tolerance < self.target_altitude) {
+            problems.push(format!(
+                "tolerance must be at least 0 and below target_altitude ({}), \
+                 got {}",
+                self.target_altitude, self.tolerance
+            ));
+        }
+
+        // The target must be measurable by the altitude sensor
+        let altitude = ChannelId::new(LONG_DISTANCE_SENSOR, 0);
+
+        if let Some(range) = registry
+            .and_then(|registry| registry.lookup(&altitude))
+            .and_then(|channel| channel.range.as_ref())
+        {
+            let target = self.target_altitude as f64;
+
+            if target < range.min || target > range.max {
+                problems.push(format!(
+                    "target_altitude {} is outside the range of {} ({} to {})",
+                    target, altitude, range.min, range.max
+                ));
+            }
+        }
+
+        if let Some(registry) = registry {
+            let mut channels = HashSet::new();
+
+            for channel in registry.channels() {
+                if !channels.insert((&channel.name, channel.instance)) {
+                    problems.push(format!(
+                        "channel {}[{}] is declared twice",
+                        channel.name, channel.instance
+                    ));
+                }
+            }
+        }
+
+        if self.max_age_ms == 0 {
+            problems.push(String::from("max_age_ms must be positive"));
+        }
+
+        if self.stale_after_ms == 0 {
+            problems.push(String::from("stale_after_ms must be positive"));
+        }
+
+        if self.dead_after_ms <= self.stale_after_ms {
+            problems.push(format!(
+                "dead_after_ms ({}) must be greater than stale_after_ms ({})",
+                self.dead_after_ms, self.stale_after_ms
+            ));
+        }
+
+        let noises = [
+            ("altitude_noise", self.estimator.altitude_noise),
+            ("acceleration_noise", self.estimator.acceleration_noise),
+        ];
+
+        for &(name, noise) in &noises {
+            if !(noise > 0.0 && noise.is_finite()) {
+                problems.push(format!(
+                    "estimator.{} must be positive, got {}",
+                    name, noise
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Valid settings that are likely mistakes
+    pub fn warnings(&self) -> Vec<String> {
+        self.publishers
+            .iter()
+            .filter(|publisher| {
+                requires_authentication(publisher.address())
+                    && publisher.key().is_none()
+            })
+            .map(|publisher| {
+                format!(
+                    "No key for {}, all of its frames will be rejected",
+                    publisher.address()
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use shared::types::FrameError;
+
+/// Frame accounting of a single publisher connection
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct PublisherStats {
+    pub frames: u64,
+    pub legacy_frames: u64,
+    pub corrupt_frames: u64,
+    // Frames with a missing or bad signature or replayed ones
+    pub unauthenticated_frames: u64,
+    // Gaps in the sequence numbers of valid frames
+    pub lost_frames: u64,
+    pub read_errors: u64,
+    // Valid frames that could not be re-encoded for mission control, e.g.
+    // JSON frames carrying NaN
+    pub forward_errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// Frame accounting keyed by publisher address
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct FrameStats {
+    publishers: HashMap<String, PublisherStats>,
+}
+
+impl FrameStats {
+    pub fn new() -> FrameStats {
+        FrameStats::default()
+    }
+
+    pub fn publisher(&mut self, address: &str) -> &mut PublisherStats {
+        self.publishers
+            .entry(address.to_string())
+            .or_insert_with(PublisherStats::default)
+    }
+
+    pub fn valid(&mut self, address: &str, legacy: bool) {
+        let stats = self.publisher(address);
+
+        stats.frames += 1;
+
+        if legacy {
+            stats.legacy_frames += 1;
+        }
+    }
+
+    pub fn corrupt(&mut self, address: &str, err: FrameError) {
+        let stats = self.publisher(address);
+
+        stats.corrupt_frames += 1;
+        stats.last_error = Some(err.to_string());
+    }
+
+    pub fn unauthenticated(&mut self, address: &str, err: FrameError) {
+        let stats = self.publisher(address);
+
+        stats.unauthenticated_frames += 1;
+        stats.last_error = Some(err.to_string());
+    }
+
+    pub fn lost(&mut self, address: &str, count: u64) {
+        self.publisher(address).lost_frames += count;
+    }
+
+    pub fn forward_error(&mut self, address: &str, err: String) {
+        let stats = self.publisher(address);
+
+        stats.forward_errors += 1;
+        stats.last_error = Some(err);
+    }
+
+    pub fn read_error(&mut self, address: &str, err: String) {
+        let stats = self.publisher(address);
+
+        stats.read_errors += 1;
+        stats.last_error = Some(err);
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use settings::beside_config;
+
+/// Calibration file of a publisher, the configured one or `default` in the
+/// directory of the config read for `config`
+pub fn calibration_path(
+    configured: Option<&String>,
+    config: Option<&str>,
+    default: &str,
+) -> String {
+    match configured {
+        Some(path) => path.clone(),
+        None => beside_config(config, default)
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// Calibration file to load, a configured file has to exist while the
+/// default one is optional. `None` leaves the publisher uncalibrated.
+pub fn existing_calibration(
+    configured: Option<&String>,
+    config: Option<&str>,
+    default: &str,
+) -> Option<String> {
+    let path = calibration_path(configured, config, default);
+
+    if configured.is_some() || Path::new(&path).exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+pub fn read_calibration<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let mut contents = String::new();
+
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| format!("could not read {} ({})", path, err))?;
+
+    serde_json::from_str(&contents)
+        .map_err(|err| format!("invalid calibration {} ({})", path, err))
+}
+
+pub fn write_calibration<T: Serialize>(
+    calibration: &T,
+    path: &str,
+) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(calibration)
+        .map_err(|err| err.to_string())?;
+
+    File::create(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|err| format!("could not write {} ({})", path, err))
+}
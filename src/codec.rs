@@ -0,0 +1,141 @@
+use std::str::FromStr;
+
+use bincode::Bounded;
+use bincode::deserialize as bincode_deserialize;
+use bincode::serialize as bincode_serialize;
+
+use rmp_serde;
+use serde_cbor;
+use serde_json;
+
+use types::{Envelope, MAX_FRAME_SIZE};
+
+lazy_static! {
+    static ref MSG_SIZE_LIMIT: Bounded = Bounded(MAX_FRAME_SIZE as u64);
+}
+
+/// Encodes the envelope that follows the frame header
+pub trait Codec {
+    fn encode(&self, envelope: &Envelope) -> Option<Vec<u8>>;
+    fn decode(&self, body: &[u8]) -> Option<Envelope>;
+}
+
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode(&self, envelope: &Envelope) -> Option<Vec<u8>> {
+        bincode_serialize(envelope, *MSG_SIZE_LIMIT).ok()
+    }
+
+    fn decode(&self, body: &[u8]) -> Option<Envelope> {
+        bincode_deserialize(body).ok()
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn encode(&self, envelope: &Envelope) -> Option<Vec<u8>> {
+        serde_cbor::to_vec(envelope).ok()
+    }
+
+    fn decode(&self, body: &[u8]) -> Option<Envelope> {
+        serde_cbor::from_slice(body).ok()
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode(&self, envelope: &Envelope) -> Option<Vec<u8>> {
+        rmp_serde::to_vec(envelope).ok()
+    }
+
+    fn decode(&self, body: &[u8]) -> Option<Envelope> {
+        rmp_serde::from_slice(body).ok()
+    }
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    // JSON has no NaN or infinities, they would be written as null and
+    // the frame could not be decoded again
+    fn encode(&self, envelope: &Envelope) -> Option<Vec<u8>> {
+        if !envelope.is_finite() {
+            return None;
+        }
+
+        serde_json::to_vec(envelope).ok()
+    }
+
+    fn decode(&self, body: &[u8]) -> Option<Envelope> {
+        serde_json::from_slice(body).ok()
+    }
+}
+
+/// Selects a codec, its tag is carried in the frame header
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum CodecKind {
+    #[serde(rename = "bincode")]
+    Bincode,
+    #[serde(rename = "cbor")]
+    Cbor,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    #[serde(rename = "json")]
+    Json,
+}
+
+impl CodecKind {
+    pub fn tag(&self) -> u8 {
+        match *self {
+            CodecKind::Bincode => 0,
+            CodecKind::Cbor => 1,
+            CodecKind::MessagePack => 2,
+            CodecKind::Json => 3,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<CodecKind> {
+        match tag {
+            0 => Some(CodecKind::Bincode),
+            1 => Some(CodecKind::Cbor),
+            2 => Some(CodecKind::MessagePack),
+            3 => Some(CodecKind::Json),
+            _ => None,
+        }
+    }
+
+    pub fn codec(&self) -> &'static Codec {
+        match *self {
+            CodecKind::Bincode => &BincodeCodec,
+            CodecKind::Cbor => &CborCodec,
+            CodecKind::MessagePack => &MessagePackCodec,
+            CodecKind::Json => &JsonCodec,
+        }
+    }
+}
+
+impl Default for CodecKind {
+    fn default() -> CodecKind {
+        CodecKind::Bincode
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(codec: &str) -> Result<CodecKind, String> {
+        match codec {
+            "bincode" => Ok(CodecKind::Bincode),
+            "cbor" => Ok(CodecKind::Cbor),
+            "msgpack" => Ok(CodecKind::MessagePack),
+            "json" => Ok(CodecKind::Json),
+            _ => Err(format!(
+                "unknown codec {} (bincode, cbor, msgpack, json)",
+                codec
+            )),
+        }
+    }
+}
@@ -0,0 +1,766 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::{PI, SQRT_2};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use types::{ChannelId, Payload, PubMessage};
+
+// Scales the median absolute deviation to the standard deviation of
+// normally distributed samples
+const MAD_SCALE: f64 = 1.4826;
+
+/// Streaming filter fed one sample at a time
+pub trait Filter: Send {
+    /// Filtered value after taking `value` into account
+    fn update(&mut self, value: f64) -> f64;
+
+    /// Forgets all previous samples
+    fn reset(&mut self);
+}
+
+/// Last samples in arrival and in sorted order. Each sample is inserted into
+/// and the oldest one removed from the sorted samples by binary search
+/// instead of re-sorting the window.
+struct SortedWindow {
+    size: usize,
+    samples: VecDeque<f64>,
+    sorted: Vec<f64>,
+}
+
+impl SortedWindow {
+    fn new(size: usize) -> SortedWindow {
+        SortedWindow {
+            size: size,
+            samples: VecDeque::with_capacity(size),
+            sorted: Vec::with_capacity(size),
+        }
+    }
+
+    fn position(&self, value: f64) -> usize {
+        match self.sorted.binary_search_by(|probe| {
+            probe.partial_cmp(&value).unwrap_or(Ordering::Less)
+        }) {
+            Ok(index) | Err(index) => index,
+        }
+    }
+
+    // NaN can't be ordered and infinities would dominate the window, both
+    // are ignored
+    fn push(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+
+        if self.samples.len() == self.size {
+            if let Some(oldest) = self.samples.pop_front() {
+                let index = self.position(oldest);
+                self.sorted.remove(index);
+            }
+        }
+
+        let index = self.position(value);
+        self.sorted.insert(index, value);
+        self.samples.push_back(value);
+    }
+
+    fn median(&self) -> Option<f64> {
+        let len = self.sorted.len();
+
+        if len == 0 {
+            None
+        } else if len % 2 == 1 {
+            Some(self.sorted[len / 2])
+        } else {
+            Some((self.sorted[len / 2 - 1] + self.sorted[len / 2]) / 2.0)
+        }
+    }
+
+    /// Median of the absolute deviations from `median`. The deviations below
+    /// and above the median are each sorted already, so they are merged
+    /// instead of sorted.
+    fn median_deviation(&self, median: f64) -> Option<f64> {
+        let len = self.sorted.len();
+
+        if len == 0 {
+            return None;
+        }
+
+        let split = self.position(median);
+        let (mut below, mut above) = (split, split);
+        let (mut lower, mut upper) = (0.0, 0.0);
+
+        for rank in 0..len / 2 + 1 {
+            let deviation = match (below > 0, above < len) {
+                (true, true)
+                    if median - self.sorted[below - 1]
+                        <= self.sorted[above] - median =>
+                {
+                    below -= 1;
+                    median - self.sorted[below]
+                }
+                (true, false) => {
+                    below -= 1;
+                    median - self.sorted[below]
+                }
+                _ => {
+                    above += 1;
+                    self.sorted[above - 1] - median
+                }
+            };
+
+            if rank == (len - 1) / 2 {
+                lower = deviation;
+            }
+
+            upper = deviation;
+        }
+
+        Some((lower + upper) / 2.0)
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.sorted.clear();
+    }
+}
+
+/// Mean of the window without its `trim` smallest and largest samples
+pub struct TrimmedMean {
+    window: SortedWindow,
+    trim: usize,
+}
+
+impl TrimmedMean {
+    pub fn new(size: usize, trim: usize) -> TrimmedMean {
+        TrimmedMean {
+            window: SortedWindow::new(size),
+            trim: trim,
+        }
+    }
+}
+
+impl Filter for TrimmedMean {
+    fn update(&mut self, value: f64) -> f64 {
+        self.window.push(value);
+
+        let sorted = &self.window.sorted;
+
+        if sorted.is_empty() {
+            return value;
+        }
+
+        // Trimmed less while the window fills up
+        let trim = self.trim.min((sorted.len() - 1) / 2);
+        let kept = &sorted[trim..sorted.len() - trim];
+
+        kept.iter().sum::<f64>() / kept.len() as f64
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+pub struct RunningMedian {
+    window: SortedWindow,
+}
+
+impl RunningMedian {
+    pub fn new(size: usize) -> RunningMedian {
+        RunningMedian {
+            window: SortedWindow::new(size),
+        }
+    }
+}
+
+impl Filter for RunningMedian {
+    fn update(&mut self, value: f64) -> f64 {
+        self.window.push(value);
+        self.window.median().unwrap_or(value)
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// Exponential moving average, `alpha` is the weight of the newest sample
+pub struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn new(alpha: f64) -> Ema {
+        Ema {
+            alpha: alpha,
+            value: None,
+        }
+    }
+}
+
+impl Filter for Ema {
+    fn update(&mut self, value: f64) -> f64 {
+        let filtered = match self.value {
+            Some(previous) if value.is_finite() => {
+                self.alpha * value + (1.0 - self.alpha) * previous
+            }
+            Some(previous) => previous,
+            None => value,
+        };
+
+        if filtered.is_finite() {
+            self.value = Some(filtered);
+        }
+
+        filtered
+    }
+
+    fn reset(&mut self) {
+        self.value = None;
+    }
+}
+
+pub struct MovingAverage {
+    size: usize,
+    samples: VecDeque<f64>,
+    sum: f64,
+}
+
+impl MovingAverage {
+    pub fn new(size: usize) -> MovingAverage {
+        MovingAverage {
+            size: size,
+            samples: VecDeque::with_capacity(size),
+            sum: 0.0,
+        }
+    }
+}
+
+impl Filter for MovingAverage {
+    fn update(&mut self, value: f64) -> f64 {
+        if !value.is_finite() {
+            return value;
+        }
+
+        if self.samples.len() == self.size {
+            if let Some(oldest) = self.samples.pop_front() {
+                self.sum -= oldest;
+            }
+        }
+
+        self.samples.push_back(value);
+        self.sum += value;
+
+        self.sum / self.samples.len() as f64
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.sum = 0.0;
+    }
+}
+
+/// Second order low-pass, coefficients as in the Audio EQ Cookbook
+pub struct LowPass {
+    b: [f64; 3],
+    a: [f64; 2],
+    // Previous inputs and outputs, unset until the first sample
+    state: Option<([f64; 2], [f64; 2])>,
+}
+
+impl LowPass {
+    /// `cutoff` and `rate` in Hz, a `q` of 1/√2 gives a Butterworth response
+    pub fn new(cutoff: f64, rate: f64, q: f64) -> LowPass {
+        let omega = 2.0 * PI * cutoff / rate;
+        let alpha = omega.sin() / (2.0 * q);
+        let cos = omega.cos();
+        let a0 = 1.0 + alpha;
+
+        LowPass {
+            b: [
+                (1.0 - cos) / 2.0 / a0,
+                (1.0 - cos) / a0,
+                (1.0 - cos) / 2.0 / a0,
+            ],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            state: None,
+        }
+    }
+}
+
+impl Filter for LowPass {
+    fn update(&mut self, value: f64) -> f64 {
+        if !value.is_finite() {
+            return value;
+        }
+
+        // Starts settled at the first sample instead of rising from zero
+        let (x, y) = self.state.unwrap_or(([value; 2], [value; 2]));
+
+        let filtered = self.b[0] * value + self.b[1] * x[0] + self.b[2] * x[1]
+            - self.a[0] * y[0] - self.a[1] * y[1];
+
+        self.state = Some(([value, x[0]], [filtered, y[0]]));
+
+        filtered
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Replaces samples deviating from the window median by more than
+/// `threshold` scaled median absolute deviations with the median
+pub struct Hampel {
+    window: SortedWindow,
+    threshold: f64,
+}
+
+impl Hampel {
+    pub fn new(size: usize, threshold: f64) -> Hampel {
+        Hampel {
+            window: SortedWindow::new(size),
+            threshold: threshold,
+        }
+    }
+}
+
+impl Filter for Hampel {
+    fn update(&mut self, value: f64) -> f64 {
+        self.window.push(value);
+
+        let median = match self.window.median() {
+            Some(median) => median,
+            None => return value,
+        };
+
+        let deviation = self.window.median_deviation(median).unwrap_or(0.0);
+
+        if (value - median).abs() > self.threshold * MAD_SCALE * deviation {
+            median
+        } else {
+            value
+        }
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// Filters applied one after another
+pub struct Chain {
+    filters: Vec<Box<Filter>>,
+}
+
+impl Filter for Chain {
+    fn update(&mut self, value: f64) -> f64 {
+        self.filters
+            .iter_mut()
+            .fold(value, |value, filter| filter.update(value))
+    }
+
+    fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+    }
+}
+
+/// Filter configuration, written as `kind:param,param,...` and chained with
+/// `|`, e.g. `hampel:7,3|ema:0.2`
+#[derive(Clone, PartialEq, Debug)]
+pub enum FilterSpec {
+    TrimmedMean { size: usize, trim: usize },
+    Median(usize),
+    Ema(f64),
+    MovingAverage(usize),
+    LowPass { cutoff: f64, rate: f64, q: f64 },
+    Hampel { size: usize, threshold: f64 },
+    Chain(Vec<FilterSpec>),
+}
+
+impl FilterSpec {
+    pub fn build(&self) -> Box<Filter> {
+        match *self {
+            FilterSpec::TrimmedMean { size, trim } => {
+                Box::new(TrimmedMean::new(size, trim))
+            }
+            FilterSpec::Median(size) => Box::new(RunningMedian::new(size)),
+            FilterSpec::Ema(alpha) => Box::new(Ema::new(alpha)),
+            FilterSpec::MovingAverage(size) => {
+                Box::new(MovingAverage::new(size))
+            }
+            FilterSpec::LowPass { cutoff, rate, q } => {
+                Box::new(LowPass::new(cutoff, rate, q))
+            }
+            FilterSpec::Hampel { size, threshold } => {
+                Box::new(Hampel::new(size, threshold))
+            }
+            FilterSpec::Chain(ref specs) => Box::new(Chain {
+                filters: specs.iter().map(|spec| spec.build()).collect(),
+            }),
+        }
+    }
+}
+
+impl FromStr for FilterSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<FilterSpec, String> {
+        if spec.contains('|') {
+            return spec.split('|')
+                .map(|spec| spec.trim().parse())
+                .collect::<Result<Vec<FilterSpec>, String>>()
+                .map(FilterSpec::Chain);
+        }
+
+        let (kind, args) = match spec.find(':') {
+            Some(index) => (&spec[..index], &spec[index + 1..]),
+            None => (spec, ""),
+        };
+
+        let params = args.split(',')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| {
+                arg.trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid parameter '{}'", arg))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+
+        // Window sizes must be whole and positive
+        let size = |size: f64| size >= 1.0 && size.fract() == 0.0;
+
+        match (kind, &params[..]) {
+            ("trimmed-mean", &[window, trim])
+                if size(window) && trim >= 0.0 && 2.0 * trim < window =>
+            {
+                Ok(FilterSpec::TrimmedMean {
+                    size: window as usize,
+                    trim: trim as usize,
+                })
+            }
+            ("median", &[window]) if size(window) => {
+                Ok(FilterSpec::Median(window as usize))
+            }
+            ("ema", &[alpha]) if alpha > 0.0 && alpha <= 1.0 => {
+                Ok(FilterSpec::Ema(alpha))
+            }
+            ("moving-average", &[window]) if size(window) => {
+                Ok(FilterSpec::MovingAverage(window as usize))
+            }
+            ("low-pass", &[cutoff, rate])
+                if cutoff > 0.0 && cutoff < rate / 2.0 =>
+            {
+                Ok(FilterSpec::LowPass {
+                    cutoff: cutoff,
+                    rate: rate,
+                    q: 1.0 / SQRT_2,
+                })
+            }
+            ("low-pass", &[cutoff, rate, q])
+                if cutoff > 0.0 && cutoff < rate / 2.0 && q > 0.0 =>
+            {
+                Ok(FilterSpec::LowPass {
+                    cutoff: cutoff,
+                    rate: rate,
+                    q: q,
+                })
+            }
+            ("hampel", &[window, threshold])
+                if size(window) && threshold > 0.0 =>
+            {
+                Ok(FilterSpec::Hampel {
+                    size: window as usize,
+                    threshold: threshold,
+                })
+            }
+            _ => Err(format!(
+                "invalid filter '{}', expected trimmed-mean:WINDOW,TRIM, \
+                 median:WINDOW, ema:ALPHA, moving-average:WINDOW, \
+                 low-pass:CUTOFF_HZ,RATE_HZ[,Q] or hampel:WINDOW,THRESHOLD, \
+                 chained with |",
+                spec
+            )),
+        }
+    }
+}
+
+impl Display for FilterSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FilterSpec::TrimmedMean { size, trim } => {
+                write!(f, "trimmed-mean:{},{}", size, trim)
+            }
+            FilterSpec::Median(size) => write!(f, "median:{}", size),
+            FilterSpec::Ema(alpha) => write!(f, "ema:{}", alpha),
+            FilterSpec::MovingAverage(size) => {
+                write!(f, "moving-average:{}", size)
+            }
+            FilterSpec::LowPass { cutoff, rate, q } => {
+                write!(f, "low-pass:{},{},{}", cutoff, rate, q)
+            }
+            FilterSpec::Hampel { size, threshold } => {
+                write!(f, "hampel:{},{}", size, threshold)
+            }
+            FilterSpec::Chain(ref specs) => {
+                let specs: Vec<String> =
+                    specs.iter().map(|spec| spec.to_string()).collect();
+
+                write!(f, "{}", specs.join("|"))
+            }
+        }
+    }
+}
+
+impl Serialize for FilterSpec {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FilterSpec {
+    fn deserialize<D>(deserializer: D) -> Result<FilterSpec, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let spec = String::deserialize(deserializer)?;
+
+        spec.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Filter of the channels named `channel`, or only of one `instance`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelFilter {
+    pub channel: String,
+    #[serde(default)]
+    pub instance: Option<u8>,
+    pub filter: FilterSpec,
+}
+
+impl ChannelFilter {
+    fn matches(&self, channel: &ChannelId) -> bool {
+        self.channel == channel.name
+            && self.instance.map_or(true, |instance| {
+                instance == channel.instance
+            })
+    }
+}
+
+/// Separate filter state per channel, built on the first sample of a channel
+pub struct ChannelFilters {
+    specs: Vec<ChannelFilter>,
+    filters: HashMap<ChannelId, Option<Box<Filter>>>,
+}
+
+impl ChannelFilters {
+    pub fn new(specs: Vec<ChannelFilter>) -> ChannelFilters {
+        ChannelFilters {
+            specs: specs,
+            filters: HashMap::new(),
+        }
+    }
+
+    /// Filters scalar payloads, integers stay integers, other payloads are
+    /// passed through
+    pub fn apply(&mut self, channel: &ChannelId, payload: Payload) -> Payload {
+        let specs = &self.specs;
+
+        let filter = self.filters.entry(channel.clone()).or_insert_with(|| {
+            specs
+                .iter()
+                .find(|spec| spec.matches(channel))
+                .map(|spec| spec.filter.build())
+        });
+
+        match (filter.as_mut(), payload) {
+            (Some(filter), Payload::Float(value)) => {
+                Payload::Float(filter.update(value))
+            }
+            (Some(filter), Payload::Integer(value)) => {
+                Payload::Integer(filter.update(value as f64).round() as i64)
+            }
+            (_, payload) => payload,
+        }
+    }
+
+    pub fn apply_all(&mut self, sample: &mut [PubMessage]) {
+        if self.specs.is_empty() {
+            return;
+        }
+
+        for msg in sample {
+            let payload = msg.payload.clone();
+            msg.payload = self.apply(&msg.channel, payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::{INFINITY, NAN, NEG_INFINITY};
+
+    use super::*;
+
+    // Deterministic samples from a small range, so windows hold duplicates
+    fn samples(count: usize) -> Vec<f64> {
+        let mut state: u32 = 12345;
+
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                ((state >> 16) % 7) as f64
+            })
+            .collect()
+    }
+
+    fn sorted(values: &[f64]) -> Vec<f64> {
+        let mut values = values.to_vec();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values
+    }
+
+    fn median(sorted: &[f64]) -> f64 {
+        let len = sorted.len();
+
+        if len % 2 == 1 {
+            sorted[len / 2]
+        } else {
+            (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0
+        }
+    }
+
+    // Re-sorts the window instead of merging
+    fn median_deviation(window: &[f64], median_value: f64) -> f64 {
+        let deviations: Vec<f64> = window
+            .iter()
+            .map(|value| (value - median_value).abs())
+            .collect();
+
+        median(&sorted(&deviations))
+    }
+
+    #[test]
+    fn sorted_window_matches_reference() {
+        let values = samples(60);
+
+        // Odd and even sizes, all wrap around several times
+        for size in 1..9 {
+            let mut window = SortedWindow::new(size);
+
+            for (index, &value) in values.iter().enumerate() {
+                window.push(value);
+
+                let start = (index + 1).saturating_sub(size);
+                let expected = sorted(&values[start..index + 1]);
+
+                assert_eq!(window.sorted, expected, "size {}", size);
+
+                let median_value = median(&expected);
+
+                assert_eq!(window.median(), Some(median_value));
+                assert_eq!(
+                    window.median_deviation(median_value),
+                    Some(median_deviation(&expected, median_value)),
+                    "size {} at {}",
+                    size,
+                    index
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sorted_window_of_equal_samples() {
+        let mut window = SortedWindow::new(4);
+
+        for _ in 0..10 {
+            window.push(3.0);
+        }
+
+        assert_eq!(window.sorted, vec![3.0; 4]);
+        assert_eq!(window.median(), Some(3.0));
+        assert_eq!(window.median_deviation(3.0), Some(0.0));
+    }
+
+    #[test]
+    fn sorted_window_ignores_non_finite_samples() {
+        let mut window = SortedWindow::new(3);
+
+        for &value in &[1.0, NAN, 2.0, INFINITY] {
+            window.push(value);
+        }
+
+        window.push(NEG_INFINITY);
+
+        assert_eq!(window.sorted, vec![1.0, 2.0]);
+        assert_eq!(window.median(), Some(1.5));
+    }
+
+    #[test]
+    fn empty_sorted_window() {
+        let window = SortedWindow::new(3);
+
+        assert_eq!(window.median(), None);
+        assert_eq!(window.median_deviation(0.0), None);
+    }
+
+    #[test]
+    fn low_pass_starts_settled() {
+        let mut low_pass = LowPass::new(5.0, 100.0, 1.0 / SQRT_2);
+
+        for _ in 0..10 {
+            assert!((low_pass.update(42.0) - 42.0).abs() < 1e-9);
+        }
+
+        low_pass.reset();
+
+        assert!((low_pass.update(-7.0) + 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn infinite_samples_are_skipped() {
+        let mut filters: Vec<Box<Filter>> = vec![
+            Box::new(Ema::new(0.5)),
+            Box::new(MovingAverage::new(3)),
+            Box::new(LowPass::new(5.0, 100.0, 1.0 / SQRT_2)),
+        ];
+
+        for filter in filters.iter_mut() {
+            filter.update(4.0);
+            filter.update(INFINITY);
+            filter.update(NEG_INFINITY);
+
+            for _ in 0..10 {
+                assert!((filter.update(4.0) - 4.0).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn hampel_replaces_outliers_with_the_median() {
+        let mut hampel = Hampel::new(5, 3.0);
+
+        // A single sample has no deviation yet and passes
+        assert_eq!(hampel.update(10.0), 10.0);
+
+        for &value in &[11.0, 9.0, 10.0, 12.0] {
+            assert_eq!(hampel.update(value), value);
+        }
+
+        // Window 9, 10, 11, 12, 100 has median 11 and deviation 1
+        assert_eq!(hampel.update(100.0), 11.0);
+        assert_eq!(hampel.update(11.0), 11.0);
+    }
+}
@@ -0,0 +1,212 @@
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use gp2d12::{Calibration, Gp2d12};
+
+use shared::calibration::{read_calibration, write_calibration};
+
+/// Calibration file written by `gp2d12_pub --calibrate`, either
+/// `{"table": [[counts, cm], ...]}` or
+/// `{"polynomial": {"coefficients": [...], "min": counts, "max": counts}}`
+#[derive(Serialize, Deserialize, Debug)]
+enum CalibrationFile {
+    #[serde(rename = "table")]
+    Table(Vec<(f32, f32)>),
+    // Highest degree first, in counts scaled to -1 to 1 between min and max
+    #[serde(rename = "polynomial")]
+    Polynomial {
+        coefficients: Vec<f64>,
+        min: f32,
+        max: f32,
+    },
+}
+
+pub fn load(path: &str) -> Result<Calibration, String> {
+    let file: CalibrationFile = read_calibration(path)?;
+
+    match file {
+        CalibrationFile::Table(points) => Calibration::table(&points)
+            .ok_or_else(|| format!("{} needs at least two points", path)),
+        CalibrationFile::Polynomial {
+            ref coefficients, ..
+        } if coefficients.is_empty() =>
+        {
+            Err(format!("{} has no coefficients", path))
+        }
+        CalibrationFile::Polynomial { min, max, .. }
+            if !(max > min && min.is_finite() && max.is_finite()) =>
+        {
+            Err(format!("{} has an invalid range of counts", path))
+        }
+        CalibrationFile::Polynomial {
+            coefficients,
+            min,
+            max,
+        } => Ok(Calibration::Polynomial {
+            coefficients: coefficients,
+            min: min,
+            max: max,
+        }),
+    }
+}
+
+pub fn save(calibration: &Calibration, path: &str) -> Result<(), String> {
+    let file = match *calibration {
+        Calibration::Table(ref points) => {
+            CalibrationFile::Table(points.clone())
+        }
+        Calibration::Polynomial {
+            ref coefficients,
+            min,
+            max,
+        } => CalibrationFile::Polynomial {
+            coefficients: coefficients.clone(),
+            min: min,
+            max: max,
+        },
+        Calibration::Nominal => {
+            return Err(String::from("the nominal curve is built in"))
+        }
+    };
+
+    write_calibration(&file, path)
+}
+
+/// Short description of the active calibration
+pub fn describe(calibration: &Calibration) -> String {
+    match *calibration {
+        Calibration::Nominal => String::from("nominal curve"),
+        Calibration::Polynomial {
+            ref coefficients,
+            min,
+            max,
+        } => format!(
+            "polynomial of degree {} over {} to {} counts",
+            coefficients.len() - 1,
+            min,
+            max
+        ),
+        Calibration::Table(ref points) => {
+            format!("table of {} points", points.len())
+        }
+    }
+}
+
+/// Curve fitted to the recorded points: `table` or `polynomial:DEGREE`
+#[derive(Clone, Copy, Debug)]
+pub enum Fit {
+    Table,
+    Polynomial(usize),
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Fit, String> {
+        let mut parts = spec.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some("table"), None) => Ok(Fit::Table),
+            (Some("polynomial"), Some(degree)) => degree
+                .parse()
+                .map(Fit::Polynomial)
+                .map_err(|_| format!("invalid degree '{}'", degree)),
+            _ => Err(format!("unknown fit '{}'", spec)),
+        }
+    }
+}
+
+impl Fit {
+    fn apply(&self, points: &[(f32, f32)]) -> Result<Calibration, String> {
+        match *self {
+            Fit::Table => Calibration::table(points)
+                .ok_or_else(|| String::from("at least two points needed")),
+            Fit::Polynomial(degree) => Calibration::fit(points, degree)
+                .ok_or_else(|| {
+                    format!(
+                        "at least {} points at different distances needed",
+                        degree + 1
+                    )
+                }),
+        }
+    }
+}
+
+// Mean raw counts of `samples` readings
+fn average(
+    gp2d12: &mut Gp2d12,
+    samples: u32,
+    interval: Duration,
+) -> Result<f32, String> {
+    let mut sum = 0.0;
+
+    for _ in 0..samples {
+        sum += gp2d12.read_raw().map_err(|err| format!("{:?}", err))? as f32;
+
+        thread::sleep(interval);
+    }
+
+    Ok(sum / samples as f32)
+}
+
+/// Records raw counts at distances entered on the terminal and fits the
+/// curve to them
+pub fn record(
+    gp2d12: &mut Gp2d12,
+    fit: Fit,
+    samples: u32,
+    interval: Duration,
+) -> Result<Calibration, String> {
+    let mut points = Vec::new();
+
+    loop {
+        println!(
+            "Place the target and enter its distance in cm, or nothing to \
+             finish"
+        );
+
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map_err(|err| err.to_string())?;
+
+        let line = line.trim();
+
+        if line.is_empty() {
+            break;
+        }
+
+        let distance: f32 = match line.parse() {
+            Ok(distance) => distance,
+            Err(_) => {
+                println!("'{}' is not a distance", line);
+                continue;
+            }
+        };
+
+        let counts = average(gp2d12, samples, interval)?;
+
+        println!("{} cm reads {:.1} counts", distance, counts);
+
+        points.push((counts, distance));
+    }
+
+    let calibration = fit.apply(&points)?;
+
+    let deviation = points
+        .iter()
+        .map(|&(counts, distance)| {
+            (calibration.distance(counts.round() as u16) - distance).abs()
+        })
+        .fold(0.0, f32::max);
+
+    println!(
+        "Fitted a {}, deviating up to {:.2} cm at the recorded points",
+        describe(&calibration),
+        deviation
+    );
+
+    Ok(calibration)
+}
@@ -1,113 +1,350 @@
 extern crate mcp3008;
-extern crate nanomsg;
 extern crate rand;
 #[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate structopt;
 
-extern crate colored;
-
-extern crate quickersort;
-
 extern crate gp2d12;
 extern crate shared;
 
-use std::thread::sleep;
-use std::time::Duration;
+mod calibration;
 
-use nanomsg::{Protocol, Socket};
+use std::process;
+use std::time::Duration;
 
-use mcp3008::Mcp3008;
+use mcp3008::{Mcp3008, Mcp3008Error};
 
 use structopt::StructOpt;
 
-use gp2d12::Gp2d12;
+use gp2d12::{Calibration, Gp2d12};
+
+use calibration::Fit;
 
 use rand::distributions::Range;
 
-use shared::types::{PubMessage, PubType};
-use shared::utils::{fill_message_decimal, publish, publish_random_values};
+use shared::codec::CodecKind;
+use shared::filter::{Filter, FilterSpec};
+use shared::sensor::{random_payload, ErrorPolicy, Runner, Sensor};
+use shared::calibration::{calibration_path, existing_calibration};
+use shared::settings::{layer, PublisherSettings, Settings};
+use shared::simulation::{Simulation, Waveform};
+use shared::ticker::OverrunPolicy;
+use shared::types::{ChannelId, Payload, PayloadKind, PubMessage,
+                    LONG_DISTANCE_SENSOR};
+
+const NAME: &'static str = "gp2d12_pub";
+
+const DEFAULT_ADDRESS: &'static str = "ipc:///tmp/gp2d12.ipc";
+
+// Loaded from the directory of the config if present, unless another
+// calibration file is configured
+const DEFAULT_CALIBRATION: &'static str = "gp2d12_calibration.json";
 
-const VALUE_BUFFER_SIZE: usize = 25;
-const EXCLUSION_RANGE: usize = VALUE_BUFFER_SIZE / 5;
-const LOGICAL_BOXPLOT_SIZE: f32 =
-    (VALUE_BUFFER_SIZE - (EXCLUSION_RANGE * 2)) as f32;
+// Readings averaged per distance when calibrating
+const CALIBRATION_SAMPLES: u32 = 500;
 
+// Flags override the configuration file and environment
 #[derive(StructOpt, Debug)]
 #[structopt(name = "gp2d12_pub")]
 struct Opt {
+    /// Configuration file, defaults to the rig config if present
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+
+    /// MCP3008 input the sensor is connected to, 0 to 7
     #[structopt(short = "a", long = "adc")]
-    adc: u8,
+    adc: Option<u8>,
+
+    /// Filter of the readings, e.g. trimmed-mean:25,5 or hampel:7,3|ema:0.2
+    #[structopt(long = "filter")]
+    filter: Option<FilterSpec>,
+
+    /// Milliseconds between two readings
+    #[structopt(short = "d", long = "delay")]
+    delay: Option<u64>,
+
+    /// Records raw counts at known distances, fits the curve and writes it to
+    /// the calibration file instead of publishing
+    #[structopt(long = "calibrate")]
+    calibrate: bool,
+
+    /// Curve fitted when calibrating: table or polynomial:DEGREE
+    #[structopt(long = "fit")]
+    fit: Option<Fit>,
+
+    /// Calibration file, defaults to gp2d12_calibration.json next to the
+    /// config if present
+    #[structopt(long = "calibration")]
+    calibration: Option<String>,
+
+    /// SPI device of the MCP3008
+    #[structopt(long = "spi-dev-path")]
+    spi_dev_path: Option<String>,
+
+    /// Instance of the channel, defaults to 0
+    #[structopt(short = "i", long = "instance")]
+    instance: Option<u8>,
 
-    #[structopt(short = "d", long = "delay", default_value = "1")]
+    /// Samples per frame
+    #[structopt(short = "b", long = "batch-size")]
+    batch_size: Option<usize>,
+
+    /// Maximum age in ms of a queued sample before the batch is published
+    #[structopt(long = "flush-interval")]
+    flush_interval: Option<u64>,
+
+    /// Frame encoding: bincode, cbor, msgpack or json
+    #[structopt(long = "codec")]
+    codec: Option<CodecKind>,
+
+    /// Also selects the section of the configuration file, defaults to
+    /// gp2d12_pub
+    #[structopt(long = "publisher-id")]
+    publisher_id: Option<String>,
+
+    /// Pre-shared key frames are signed with, required on TCP transports
+    #[structopt(long = "key")]
+    key: Option<String>,
+
+    /// Simulated waveform without hardware, e.g. free-fall:80,5,980.665
+    #[structopt(long = "simulate")]
+    simulate: Option<Waveform>,
+
+    /// Standard deviation of the noise added to simulated values
+    #[structopt(long = "noise")]
+    noise: Option<f64>,
+
+    /// Seed of the simulation noise
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Handling of failed reads and writes: retry:ATTEMPTS,BACKOFF_MS, skip
+    /// or escalate:FAILURES
+    #[structopt(long = "on-error")]
+    on_error: Option<ErrorPolicy>,
+
+    /// Handling of reads that overrun their period: skip or catch-up
+    #[structopt(long = "overrun")]
+    overrun: Option<OverrunPolicy>,
+
+    /// Address the frames are published on
+    address: Option<String>,
+}
+
+/// `[gp2d12_pub]` section of the configuration file, `adc` has no default
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct Gp2d12Settings {
+    // Smooths the raw readings, simulated values are not filtered
+    filter: FilterSpec,
+    adc: Option<u8>,
     delay: u64,
+    // Nominal curve if unset and there is no gp2d12_calibration.json next
+    // to the config
+    calibration: Option<String>,
+    spi_dev_path: String,
+    instance: u8,
+    batch_size: usize,
+    flush_interval: u64,
+}
 
-    #[structopt(long = "spi-dev-path", default_value = "/dev/spidev0.0")]
+impl Default for Gp2d12Settings {
+    fn default() -> Gp2d12Settings {
+        Gp2d12Settings {
+            // Mean of the 15 middle values of the last 25
+            filter: FilterSpec::TrimmedMean { size: 25, trim: 5 },
+            adc: None,
+            delay: 1,
+            calibration: None,
+            spi_dev_path: String::from("/dev/spidev0.0"),
+            instance: 0,
+            batch_size: 1,
+            flush_interval: 10,
+        }
+    }
+}
+
+struct Gp2d12Sensor {
     spi_dev_path: String,
+    adc: u8,
+    calibration: Calibration,
+    gp2d12: Option<Gp2d12>,
+    filter: Box<Filter>,
+    simulation: Option<Simulation>,
+    // Instead of creating new messages modify an existing one
+    msg: PubMessage,
+}
+
+impl Sensor for Gp2d12Sensor {
+    type Error = Mcp3008Error;
+
+    fn init(&mut self) -> Result<(), Mcp3008Error> {
+        let mcp3008 = Mcp3008::new(&self.spi_dev_path)?;
+        self.gp2d12 = Some(Gp2d12::with_calibration(
+            mcp3008,
+            self.adc,
+            self.calibration.clone(),
+        ));
+
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Vec<PubMessage>, Mcp3008Error> {
+        let gp2d12 = self.gp2d12.as_mut().expect("sensor not initialized");
+
+        let distance = gp2d12.read()? as f64;
+
+        self.msg.payload = Payload::Float(self.filter.update(distance));
+
+        Ok(vec![self.msg.clone()])
+    }
+
+    fn channels(&self) -> Vec<(ChannelId, PayloadKind)> {
+        vec![(self.msg.channel.clone(), PayloadKind::Float)]
+    }
+
+    fn simulate(&mut self) -> Vec<PubMessage> {
+        self.msg.payload = match self.simulation {
+            Some(ref mut simulation) => simulation.payload(PayloadKind::Float),
+            None => random_payload(PayloadKind::Float, &Range::new(0.0, 80.0)),
+        };
+
+        vec![self.msg.clone()]
+    }
+}
+
+// Reports the active calibration, a configured file has to load
+fn load_calibration(
+    configured: Option<&String>,
+    config: Option<&str>,
+) -> Calibration {
+    let path = match existing_calibration(
+        configured,
+        config,
+        DEFAULT_CALIBRATION,
+    ) {
+        Some(path) => path,
+        None => {
+            println!("No calibration, using the nominal curve");
+            return Calibration::Nominal;
+        }
+    };
 
-    #[structopt(default_value = "ipc:///tmp/gp2d12.ipc")]
-    address: String,
+    let calibration = calibration::load(&path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    println!(
+        "Using calibration {} ({})",
+        path,
+        calibration::describe(&calibration)
+    );
+
+    calibration
 }
 
 fn main() {
     let opt = Opt::from_args();
 
-    // Create nanomsg publisher socket
-    let mut socket =
-        Socket::new(Protocol::Pub).expect("could not create socket");
-    socket
-        .bind(opt.address.as_str())
-        .expect("socket bind failed");
+    let settings =
+        Settings::load(opt.config.as_ref().map(String::as_str), false)
+            .expect("could not load configuration");
+    let section = opt.publisher_id
+        .clone()
+        .unwrap_or_else(|| NAME.to_string());
 
-    // Instead of creating new messages modify an existing one
-    let mut msg = PubMessage {
-        pub_type: PubType::LongDistanceSensor,
-        integral: 0,
-        decimal: 0.0,
-    };
+    let mut publisher: PublisherSettings = settings
+        .section(&section)
+        .expect("invalid publisher configuration");
+    let mut gp2d12: Gp2d12Settings = settings
+        .section(&section)
+        .expect("invalid gp2d12 configuration");
 
-    let sleep_duration = Duration::from_millis(opt.delay);
+    layer(&mut gp2d12.filter, opt.filter);
+    layer(&mut gp2d12.adc, opt.adc.map(Some));
+    layer(&mut gp2d12.delay, opt.delay);
+    layer(&mut gp2d12.calibration, opt.calibration.map(Some));
+    layer(&mut gp2d12.spi_dev_path, opt.spi_dev_path);
+    layer(&mut gp2d12.instance, opt.instance);
+    layer(&mut gp2d12.batch_size, opt.batch_size);
+    layer(&mut gp2d12.flush_interval, opt.flush_interval);
+    layer(&mut publisher.address, opt.address.map(Some));
+    layer(&mut publisher.publisher_id, opt.publisher_id.map(Some));
+    layer(&mut publisher.codec, opt.codec);
+    layer(&mut publisher.key, opt.key.map(Some));
+    layer(&mut publisher.simulate, opt.simulate.map(Some));
+    layer(&mut publisher.noise, opt.noise);
+    layer(&mut publisher.seed, opt.seed);
+    layer(&mut publisher.on_error, opt.on_error);
+    layer(&mut publisher.overrun, opt.overrun);
 
-    if let Ok(mcp3008) = Mcp3008::new(&opt.spi_dev_path) {
-        let mut gp2d12 = Gp2d12::new(mcp3008, opt.adc);
+    let adc = gp2d12.adc.unwrap_or_else(|| {
+        eprintln!("No adc configured, set adc in [{}] or pass --adc", section);
+        process::exit(1);
+    });
 
-        let mut value_buffer = [0_f32; VALUE_BUFFER_SIZE];
+    let interval = Duration::from_millis(gp2d12.delay);
 
-        let mut write_index: usize = 0;
-        let mut avg: f32 = 0.0;
+    if opt.calibrate {
+        let path = calibration_path(
+            gp2d12.calibration.as_ref(),
+            opt.config.as_ref().map(String::as_str),
+            DEFAULT_CALIBRATION,
+        );
 
-        loop {
-            match gp2d12.read() {
-                Ok(value) => {
-                    value_buffer[write_index] = value;
-                    write_index = (write_index + 1) % VALUE_BUFFER_SIZE;
-                }
-                Err(err) => panic!(err),
-            }
+        let result = Mcp3008::new(&gp2d12.spi_dev_path)
+            .map_err(|err| format!("{} ({:?})", gp2d12.spi_dev_path, err))
+            .and_then(|mcp3008| {
+                let mut sensor = Gp2d12::new(mcp3008, adc);
+                let fit = opt.fit.unwrap_or(Fit::Table);
 
-            let mut boxplot_array = value_buffer;
-            quickersort::sort_floats(&mut boxplot_array[..]);
+                calibration::record(
+                    &mut sensor,
+                    fit,
+                    CALIBRATION_SAMPLES,
+                    interval,
+                )
+            })
+            .and_then(|calibration| calibration::save(&calibration, &path));
 
-            for value in boxplot_array
-                [EXCLUSION_RANGE..VALUE_BUFFER_SIZE - EXCLUSION_RANGE]
-                .iter()
-            {
-                avg += value;
+        match result {
+            Ok(_) => println!("Wrote calibration to {}", path),
+            Err(err) => {
+                eprintln!("Calibration failed ({})", err);
+                process::exit(1);
             }
+        }
+
+        return;
+    }
 
-            msg = fill_message_decimal(avg / LOGICAL_BOXPLOT_SIZE, msg);
+    let sensor = Gp2d12Sensor {
+        spi_dev_path: gp2d12.spi_dev_path.clone(),
+        adc: adc,
+        calibration: load_calibration(
+            gp2d12.calibration.as_ref(),
+            opt.config.as_ref().map(String::as_str),
+        ),
+        gp2d12: None,
+        filter: gp2d12.filter.build(),
+        simulation: publisher.simulation(interval),
+        msg: PubMessage {
+            channel: ChannelId::new(LONG_DISTANCE_SENSOR, gp2d12.instance),
+            payload: Payload::Float(0.0),
+        },
+    };
 
-            avg = 0.0;
+    let mut config =
+        publisher.runner_config(&section, DEFAULT_ADDRESS, interval);
+    config.batch_size = gp2d12.batch_size;
+    config.flush_interval = Duration::from_millis(gp2d12.flush_interval);
 
-            publish(&mut socket, &msg);
+    let mut runner = Runner::new(sensor, config).expect("socket bind failed");
 
-            sleep(sleep_duration);
-        }
-    } else {
-        publish_random_values(
-            socket,
-            msg,
-            sleep_duration,
-            Range::new(0.0, 80.0),
-        );
+    if let Err(err) = runner.run() {
+        eprintln!("{}", err);
+        process::exit(1);
     }
 }
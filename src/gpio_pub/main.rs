@@ -1,90 +1,225 @@
-extern crate nanomsg;
 extern crate rand;
 extern crate rppal;
 #[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate structopt;
 
 extern crate shared;
 
-use std::thread::sleep;
+use std::process;
 use std::time::Duration;
 
-use nanomsg::{Protocol, Socket};
-
-use rppal::gpio::{Gpio, Level, Mode};
+use rppal::gpio::{Error as GpioError, Gpio, Level, Mode};
 
 use rand::distributions::Range;
 
-use shared::types::{str_to_pub_type, PubMessage};
-use shared::utils::{fill_message_integral, publish, publish_random_values};
+use shared::codec::CodecKind;
+use shared::sensor::{random_payload, ErrorPolicy, Runner, Sensor};
+use shared::settings::{layer, PublisherSettings, Settings};
+use shared::simulation::{Simulation, Waveform};
+use shared::ticker::OverrunPolicy;
+use shared::types::{ChannelId, Payload, PayloadKind, PubMessage};
 
 use structopt::StructOpt;
 
+const NAME: &'static str = "gpio_pub";
+
+const DEFAULT_ADDRESS: &'static str = "ipc:///tmp/gpio_1.ipc";
+
+// Flags override the configuration file and environment
 #[derive(StructOpt, Debug)]
 #[structopt(name = "gpio_pub")]
 struct Opt {
+    /// Configuration file, defaults to the rig config if present
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+
+    /// BCM number of the input pin
     #[structopt(short = "p", long = "pin")]
-    pin: u8,
+    pin: Option<u8>,
+
+    /// Milliseconds between two readings
+    #[structopt(short = "d", long = "delay")]
+    delay: Option<u64>,
+
+    /// Channel the readings are published on
+    #[structopt(short = "t", long = "channel")]
+    channel: Option<String>,
+
+    /// Instance of the channel, defaults to 0
+    #[structopt(short = "i", long = "instance")]
+    instance: Option<u8>,
+
+    /// Frame encoding: bincode, cbor, msgpack or json
+    #[structopt(long = "codec")]
+    codec: Option<CodecKind>,
+
+    /// Also selects the section of the configuration file, defaults to
+    /// gpio_pub
+    #[structopt(long = "publisher-id")]
+    publisher_id: Option<String>,
+
+    /// Pre-shared key frames are signed with, required on TCP transports
+    #[structopt(long = "key")]
+    key: Option<String>,
 
-    #[structopt(short = "d", long = "delay", default_value = "1")]
+    /// Simulated waveform without hardware, e.g. free-fall:80,5,980.665
+    #[structopt(long = "simulate")]
+    simulate: Option<Waveform>,
+
+    /// Standard deviation of the noise added to simulated values
+    #[structopt(long = "noise")]
+    noise: Option<f64>,
+
+    /// Seed of the simulation noise
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Handling of failed reads and writes: retry:ATTEMPTS,BACKOFF_MS, skip
+    /// or escalate:FAILURES
+    #[structopt(long = "on-error")]
+    on_error: Option<ErrorPolicy>,
+
+    /// Handling of reads that overrun their period: skip or catch-up
+    #[structopt(long = "overrun")]
+    overrun: Option<OverrunPolicy>,
+
+    /// Address the frames are published on
+    address: Option<String>,
+}
+
+/// `[gpio_pub]` section of the configuration file, `pin` and `channel` have
+/// no defaults
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct GpioSettings {
+    pin: Option<u8>,
     delay: u64,
+    channel: Option<String>,
+    instance: u8,
+}
 
-    #[structopt(short = "t", long = "pub-type")]
-    pub_type: String,
+impl Default for GpioSettings {
+    fn default() -> GpioSettings {
+        GpioSettings {
+            pin: None,
+            delay: 1,
+            channel: None,
+            instance: 0,
+        }
+    }
+}
 
-    #[structopt(default_value = "ipc:///tmp/gpio_1.ipc")]
-    address: String,
+struct GpioSensor {
+    pin: u8,
+    gpio: Option<Gpio>,
+    simulation: Option<Simulation>,
+    // Instead of creating new messages modify an existing one
+    msg: PubMessage,
 }
 
-fn main() {
-    let opt = Opt::from_args();
+impl Sensor for GpioSensor {
+    type Error = GpioError;
 
-    // Obtain config values
-    let pin = opt.pin;
-    let address = opt.address;
-    let pub_type =
-        str_to_pub_type(&opt.pub_type).expect("invalid publisher type");
+    fn init(&mut self) -> Result<(), GpioError> {
+        let mut gpio = Gpio::new()?;
+        gpio.set_mode(self.pin, Mode::Input);
 
-    // Create nanomsg publisher socket
-    let mut socket = Socket::new(Protocol::Pub).unwrap();
-    socket.bind(address.as_str()).expect("socket bind failed");
+        self.gpio = Some(gpio);
 
-    // Instead of creating new messages modify an existing one
-    let mut msg = PubMessage {
-        pub_type: pub_type,
-        integral: 0,
-        decimal: 0.0,
-    };
+        Ok(())
+    }
 
-    let sleep_duration = Duration::from_millis(opt.delay);
+    fn read(&mut self) -> Result<Vec<PubMessage>, GpioError> {
+        let gpio = self.gpio.as_mut().expect("sensor not initialized");
 
-    // Generate random values if not running on Raspberry Pi
-    if let Ok(mut gpio) = Gpio::new() {
-        gpio.set_mode(pin, Mode::Input);
+        let level = gpio.read(self.pin)?;
 
-        loop {
-            if let Ok(level) = gpio.read(pin) {
-                msg = fill_message_integral(
-                    match level {
-                        Level::High => 1,
-                        Level::Low => 0,
-                    },
-                    msg,
-                );
-            } else {
-                panic!("could not read from Gpio pin")
-            }
+        self.msg.payload = Payload::Bool(match level {
+            Level::High => true,
+            Level::Low => false,
+        });
 
-            publish(&mut socket, &msg);
+        Ok(vec![self.msg.clone()])
+    }
 
-            sleep(sleep_duration);
-        }
-    } else {
-        publish_random_values(
-            socket,
-            msg,
-            sleep_duration,
-            Range::new(0.0, 2.0),
+    fn channels(&self) -> Vec<(ChannelId, PayloadKind)> {
+        vec![(self.msg.channel.clone(), PayloadKind::Bool)]
+    }
+
+    fn simulate(&mut self) -> Vec<PubMessage> {
+        self.msg.payload = match self.simulation {
+            Some(ref mut simulation) => simulation.payload(PayloadKind::Bool),
+            None => random_payload(PayloadKind::Bool, &Range::new(0.0, 2.0)),
+        };
+
+        vec![self.msg.clone()]
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let settings =
+        Settings::load(opt.config.as_ref().map(String::as_str), false)
+            .expect("could not load configuration");
+    let section = opt.publisher_id
+        .clone()
+        .unwrap_or_else(|| NAME.to_string());
+
+    let mut publisher: PublisherSettings = settings
+        .section(&section)
+        .expect("invalid publisher configuration");
+    let mut gpio: GpioSettings = settings
+        .section(&section)
+        .expect("invalid gpio configuration");
+
+    layer(&mut gpio.pin, opt.pin.map(Some));
+    layer(&mut gpio.delay, opt.delay);
+    layer(&mut gpio.channel, opt.channel.map(Some));
+    layer(&mut gpio.instance, opt.instance);
+    layer(&mut publisher.address, opt.address.map(Some));
+    layer(&mut publisher.publisher_id, opt.publisher_id.map(Some));
+    layer(&mut publisher.codec, opt.codec);
+    layer(&mut publisher.key, opt.key.map(Some));
+    layer(&mut publisher.simulate, opt.simulate.map(Some));
+    layer(&mut publisher.noise, opt.noise);
+    layer(&mut publisher.seed, opt.seed);
+    layer(&mut publisher.on_error, opt.on_error);
+    layer(&mut publisher.overrun, opt.overrun);
+
+    let pin = gpio.pin.unwrap_or_else(|| {
+        eprintln!("No pin configured, set pin in [{}] or pass --pin", section);
+        process::exit(1);
+    });
+
+    let channel = gpio.channel.unwrap_or_else(|| {
+        eprintln!(
+            "No channel configured, set channel in [{}] or pass --channel",
+            section
         );
+        process::exit(1);
+    });
+
+    let interval = Duration::from_millis(gpio.delay);
+
+    let sensor = GpioSensor {
+        pin: pin,
+        gpio: None,
+        simulation: publisher.simulation(interval),
+        msg: PubMessage {
+            channel: ChannelId::new(&channel, gpio.instance),
+            payload: Payload::Bool(false),
+        },
+    };
+
+    let config = publisher.runner_config(&section, DEFAULT_ADDRESS, interval);
+
+    let mut runner = Runner::new(sensor, config).expect("socket bind failed");
+
+    if let Err(err) = runner.run() {
+        eprintln!("{}", err);
+        process::exit(1);
     }
 }
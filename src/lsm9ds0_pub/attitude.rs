@@ -0,0 +1,111 @@
+use std::f64::consts::PI;
+
+use shared::types::Vector3;
+use shared::units::GRAVITY;
+
+// The accelerometer only measures gravity while its magnitude stays this
+// close to 1 g, i.e. not during a fall or while boosting
+const GRAVITY_TOLERANCE: f64 = 0.15;
+
+// Keeps an angle within -π to π
+fn wrap(angle: f64) -> f64 {
+    angle - 2.0 * PI * ((angle + PI) / (2.0 * PI)).floor()
+}
+
+/// Roll and pitch in radians given by the direction of gravity, `None` if
+/// the acceleration is not dominated by gravity
+pub fn from_gravity(acc: &Vector3) -> Option<(f64, f64)> {
+    let magnitude =
+        (acc.x * acc.x + acc.y * acc.y + acc.z * acc.z).sqrt() / GRAVITY;
+
+    if (magnitude - 1.0).abs() > GRAVITY_TOLERANCE {
+        return None;
+    }
+
+    //                    y
+    //      roll = atan2(---)
+    //                    z
+
+    let roll = acc.y.atan2(acc.z);
+
+    //                                 -x
+    //      pitch = atan(-------------------------------)
+    //                    y * sin(roll) + z * cos(roll)
+    //
+    let pitch = if acc.y * roll.sin() + acc.z * roll.cos() == 0.0 {
+        if acc.x > 0.0 {
+            PI / 2.0
+        } else {
+            -PI / 2.0
+        }
+    } else {
+        (-acc.x / (acc.y * roll.sin() + acc.z * roll.cos())).atan()
+    };
+
+    Some((roll, pitch))
+}
+
+/// Complementary filter, the integrated gyroscope rates are pulled towards
+/// the attitude given by gravity whenever the accelerometer measures it.
+/// In free fall the attitude is carried on by the gyroscope alone.
+pub struct Attitude {
+    // Seconds, longer trusts the gyroscope more
+    time_constant: f64,
+    // Roll and pitch in radians, unset until the first sample
+    angles: Option<(f64, f64)>,
+}
+
+impl Attitude {
+    pub fn new(time_constant: f64) -> Attitude {
+        Attitude {
+            time_constant: time_constant,
+            angles: None,
+        }
+    }
+
+    /// Takes the acceleration in m/s² and the rotation rates about x, y and
+    /// z in deg/s measured `dt` seconds after the previous sample, returns
+    /// roll and pitch in degrees
+    pub fn update(
+        &mut self,
+        acc: &Vector3,
+        rates: &Vector3,
+        dt: f64,
+    ) -> (f64, f64) {
+        let measured = from_gravity(acc);
+
+        let (roll, pitch) = match self.angles {
+            Some((roll, pitch)) => {
+                let p = rates.x.to_radians();
+                let q = rates.y.to_radians();
+                let r = rates.z.to_radians();
+
+                // Body rates to Euler angle rates
+                let roll_rate =
+                    p + (q * roll.sin() + r * roll.cos()) * pitch.tan();
+                let pitch_rate = q * roll.cos() - r * roll.sin();
+
+                let roll = wrap(roll + roll_rate * dt);
+                let pitch = pitch + pitch_rate * dt;
+
+                match measured {
+                    Some((measured_roll, measured_pitch)) => {
+                        let weight = dt / (self.time_constant + dt);
+
+                        (
+                            wrap(roll + weight * wrap(measured_roll - roll)),
+                            pitch + weight * (measured_pitch - pitch),
+                        )
+                    }
+                    None => (roll, pitch),
+                }
+            }
+            // Started while falling, level is the best guess
+            None => measured.unwrap_or((0.0, 0.0)),
+        };
+
+        self.angles = Some((roll, pitch));
+
+        (roll.to_degrees(), pitch.to_degrees())
+    }
+}
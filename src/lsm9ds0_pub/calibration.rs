@@ -0,0 +1,98 @@
+use shared::types::Vector3;
+#[cfg(target_os = "linux")]
+use shared::units::GRAVITY;
+
+/// Orientations of the six-position calibration by the index of the axis
+/// pointing up and its sign
+#[cfg(target_os = "linux")]
+pub const ORIENTATIONS: [(&'static str, usize, f64); 6] = [
+    ("z axis up", 2, 1.0),
+    ("z axis down", 2, -1.0),
+    ("x axis up", 0, 1.0),
+    ("x axis down", 0, -1.0),
+    ("y axis up", 1, 1.0),
+    ("y axis down", 1, -1.0),
+];
+
+#[cfg(target_os = "linux")]
+pub fn axis(vector: &Vector3, index: usize) -> f64 {
+    match index {
+        0 => vector.x,
+        1 => vector.y,
+        _ => vector.z,
+    }
+}
+
+/// Accelerometer offset and scale and gyroscope bias, written by
+/// `lsm9ds0_pub --calibrate`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Calibration {
+    // Full scale in g the calibration was recorded at
+    pub accel_range: u8,
+    // Subtracted from the accelerometer readings in m/s²
+    pub accel_offset: Vector3,
+    // Applied once the offset is removed
+    pub accel_scale: Vector3,
+    // Subtracted from the gyroscope readings in deg/s
+    pub gyro_bias: Vector3,
+}
+
+impl Calibration {
+    /// Fits offset and scale to the mean acceleration of every orientation,
+    /// given in the order of `ORIENTATIONS`
+    #[cfg(target_os = "linux")]
+    pub fn fit(
+        accel: &[Vector3; 6],
+        gyro_bias: Vector3,
+        accel_range: u8,
+    ) -> Calibration {
+        let mut offset = [0.0; 3];
+        let mut scale = [1.0; 3];
+
+        for (index, &(_, up_axis, sign)) in ORIENTATIONS.iter().enumerate() {
+            if sign < 0.0 {
+                continue;
+            }
+
+            // The orientation pointing the same axis down follows
+            let up = axis(&accel[index], up_axis);
+            let down = axis(&accel[index + 1], up_axis);
+
+            offset[up_axis] = (up + down) / 2.0;
+            scale[up_axis] = 2.0 * GRAVITY / (up - down);
+        }
+
+        Calibration {
+            accel_range: accel_range,
+            accel_offset: Vector3 {
+                x: offset[0],
+                y: offset[1],
+                z: offset[2],
+            },
+            accel_scale: Vector3 {
+                x: scale[0],
+                y: scale[1],
+                z: scale[2],
+            },
+            gyro_bias: gyro_bias,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn accel(&self, raw: &Vector3) -> Vector3 {
+        Vector3 {
+            x: (raw.x - self.accel_offset.x) * self.accel_scale.x,
+            y: (raw.y - self.accel_offset.y) * self.accel_scale.y,
+            z: (raw.z - self.accel_offset.z) * self.accel_scale.z,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn gyro(&self, raw: &Vector3) -> Vector3 {
+        Vector3 {
+            x: raw.x - self.gyro_bias.x,
+            y: raw.y - self.gyro_bias.y,
+            z: raw.z - self.gyro_bias.z,
+        }
+    }
+}
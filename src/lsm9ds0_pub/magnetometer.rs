@@ -0,0 +1,69 @@
+use shared::types::Vector3;
+
+/// Hard and soft iron correction of the magnetometer, the field is
+/// corrected as `soft_iron * (raw - hard_iron)`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct IronCorrection {
+    // Offset caused by magnetized parts of the rig in gauss
+    pub hard_iron: Vector3,
+    // Rows of the matrix undoing the distortion caused by nearby metal
+    pub soft_iron: [[f64; 3]; 3],
+}
+
+impl Default for IronCorrection {
+    fn default() -> IronCorrection {
+        IronCorrection {
+            hard_iron: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            soft_iron: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+}
+
+impl IronCorrection {
+    pub fn correct(&self, raw: &Vector3) -> Vector3 {
+        let offset = [
+            raw.x - self.hard_iron.x,
+            raw.y - self.hard_iron.y,
+            raw.z - self.hard_iron.z,
+        ];
+
+        let row = |index: usize| {
+            self.soft_iron[index]
+                .iter()
+                .zip(offset.iter())
+                .map(|(factor, value)| factor * value)
+                .sum::<f64>()
+        };
+
+        Vector3 {
+            x: row(0),
+            y: row(1),
+            z: row(2),
+        }
+    }
+}
+
+/// Heading in degrees from 0 to 360, clockwise from magnetic north along
+/// the x axis. The corrected field is rotated back to the horizontal plane
+/// with roll and pitch in degrees.
+pub fn heading(field: &Vector3, roll: f64, pitch: f64) -> f64 {
+    let (roll, pitch) = (roll.to_radians(), pitch.to_radians());
+
+    let horizontal_x = field.x * pitch.cos()
+        + field.y * roll.sin() * pitch.sin()
+        + field.z * roll.cos() * pitch.sin();
+    let horizontal_y = field.y * roll.cos() - field.z * roll.sin();
+
+    let heading = (-horizontal_y).atan2(horizontal_x).to_degrees();
+
+    if heading < 0.0 {
+        heading + 360.0
+    } else {
+        heading
+    }
+}
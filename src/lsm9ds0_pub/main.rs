@@ -3,19 +3,28 @@ extern crate lazy_static;
 
 #[cfg(target_os = "linux")]
 extern crate i2cdev;
-extern crate nanomsg;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate structopt;
 
 extern crate shared;
 
-use std::thread::sleep;
-use std::time::Duration;
-
-use std::f32::consts::PI;
+mod attitude;
+mod calibration;
+mod magnetometer;
+mod range;
 
-use nanomsg::{Protocol, Socket};
+#[cfg(target_os = "linux")]
+use std::io;
+use std::process;
+#[cfg(target_os = "linux")]
+use std::thread;
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
 
 #[cfg(target_os = "linux")]
 use i2cdev::core::I2CDevice;
@@ -26,219 +35,776 @@ use rand::distributions::Range;
 
 use structopt::StructOpt;
 
-use shared::types::{PubMessage, PubType};
-use shared::utils::{fill_message_decimal, fill_message_integral, publish,
-                    publish_random_values};
+use shared::codec::CodecKind;
+use shared::sensor::{random_payload, ErrorPolicy, Runner, Sensor};
+use shared::calibration::{calibration_path, existing_calibration,
+                          read_calibration, write_calibration};
+use shared::settings::{layer, PublisherSettings, Settings};
+use shared::simulation::{Simulation, Waveform};
+use shared::ticker::OverrunPolicy;
+use shared::types::{ChannelId, Payload, PayloadKind, PubMessage, Vector3,
+                    ACCELERATION, ACCELEROMETER_Z, GYROSCOPE_X, GYROSCOPE_Y,
+                    HEADING, IS_FALLING, MAGNETOMETER};
+use shared::units::GRAVITY;
+
+use attitude::Attitude;
+#[cfg(target_os = "linux")]
+use calibration::{axis, ORIENTATIONS};
+use calibration::Calibration;
+use magnetometer::IronCorrection;
+use range::{AccelDataRate, AccelRange};
 
 lazy_static! {
-    static ref BETWEEN: Range<f32> = Range::new(
+    static ref BETWEEN: Range<f64> = Range::new(
         -180.0, 180.0);
 }
 
-const GRAVITY: f32 = 9.80665;
-
 const ADDRESS_ACCELMAG: u16 = 0x1d;
+const ADDRESS_GYRO: u16 = 0x6b;
+
+const GYRORANGE_500DPS: u8 = 0b01 << 4;
+const GYRO_DPS_DIGIT_500DPS: f64 = 0.0175;
 
-const ACCELRANGE_2G: u8 = 0b000 << 3;
-const ACCEL_MG_LSB_2G: f32 = 0.061;
+const MAGGAIN_2GAUSS: u8 = 0b00 << 5;
+const MAG_MGAUSS_LSB_2GAUSS: f64 = 0.08;
 
 const REGISTER_CTRL_REG1_XM: u8 = 0x20;
 const REGISTER_CTRL_REG2_XM: u8 = 0x21;
 const REGISTER_CTRL_REG5_XM: u8 = 0x24;
+const REGISTER_CTRL_REG6_XM: u8 = 0x25;
+const REGISTER_CTRL_REG7_XM: u8 = 0x26;
 
+const REGISTER_OUT_X_L_M: u8 = 0x08;
 const REGISTER_OUT_X_L_A: u8 = 0x28;
 
+const REGISTER_CTRL_REG1_G: u8 = 0x20;
+const REGISTER_CTRL_REG4_G: u8 = 0x23;
+
+const REGISTER_OUT_X_L_G: u8 = 0x28;
+
+// Set in the register address to read several registers in one transfer
+const AUTO_INCREMENT: u8 = 0x80;
+
+// Keeps low and high byte of a reading from different samples
+const BLOCK_DATA_UPDATE_XM: u8 = 0b1000;
+const BLOCK_DATA_UPDATE_G: u8 = 0b10000000;
+
+const NAME: &'static str = "lsm9ds0_pub";
+
+// Loaded from the directory of the config if present, unless another
+// calibration file is configured
+const DEFAULT_CALIBRATION: &'static str = "lsm9ds0_calibration.json";
+
+// Readings averaged per orientation when calibrating
+#[cfg(target_os = "linux")]
+const CALIBRATION_SAMPLES: u32 = 200;
+
+const DEFAULT_ADDRESS: &'static str = "ipc:///tmp/lsm9ds0.ipc";
+
+// Flags override the configuration file and environment
 #[derive(StructOpt, Debug)]
 #[structopt(name = "lsm9ds0_pub")]
 struct Opt {
-    #[structopt(long = "i2c-dev-path", default_value = "/dev/i2c-1")]
+    /// Configuration file, defaults to the rig config if present
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+
+    /// I2C bus of the LSM9DS0
+    #[structopt(long = "i2c-dev-path")]
+    i2c_dev_path: Option<String>,
+
+    /// Instance of the channel, defaults to 0
+    #[structopt(short = "i", long = "instance")]
+    instance: Option<u8>,
+
+    /// Full scale of the accelerometer: 2, 4, 6, 8 or 16 g
+    #[structopt(long = "accel-range")]
+    accel_range: Option<AccelRange>,
+
+    /// Output data rate of the accelerometer in Hz: 3.125, 6.25, 12.5, 25,
+    /// 50, 100, 200, 400, 800 or 1600
+    #[structopt(long = "accel-data-rate")]
+    accel_data_rate: Option<AccelDataRate>,
+
+    /// Samples published per second
+    #[structopt(short = "r", long = "rate")]
+    rate: Option<f64>,
+
+    /// Records a calibration in six orientations at rest and writes it to the
+    /// calibration file instead of publishing
+    #[structopt(long = "calibrate")]
+    calibrate: bool,
+
+    /// Calibration file, defaults to lsm9ds0_calibration.json next to the
+    /// config if present
+    #[structopt(long = "calibration")]
+    calibration: Option<String>,
+
+    /// Seconds the gyroscope is trusted over gravity when estimating the
+    /// attitude
+    #[structopt(long = "attitude-time-constant")]
+    attitude_time_constant: Option<f64>,
+
+    /// Frame encoding: bincode, cbor, msgpack or json
+    #[structopt(long = "codec")]
+    codec: Option<CodecKind>,
+
+    /// Also selects the section of the configuration file, defaults to
+    /// lsm9ds0_pub
+    #[structopt(long = "publisher-id")]
+    publisher_id: Option<String>,
+
+    /// Pre-shared key frames are signed with, required on TCP transports
+    #[structopt(long = "key")]
+    key: Option<String>,
+
+    /// Simulated z acceleration in m/s² without hardware, a free fall
+    /// simulates the acceleration of the drop, e.g. free-fall:0.8,5,9.80665
+    #[structopt(long = "simulate")]
+    simulate: Option<Waveform>,
+
+    /// Standard deviation of the noise added to simulated values
+    #[structopt(long = "noise")]
+    noise: Option<f64>,
+
+    /// Seed of the simulation noise
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Handling of failed reads and writes: retry:ATTEMPTS,BACKOFF_MS, skip
+    /// or escalate:FAILURES
+    #[structopt(long = "on-error")]
+    on_error: Option<ErrorPolicy>,
+
+    /// Handling of reads that overrun their period: skip or catch-up
+    #[structopt(long = "overrun")]
+    overrun: Option<OverrunPolicy>,
+
+    /// Address the frames are published on
+    address: Option<String>,
+}
+
+/// `[lsm9ds0_pub]` section of the configuration file
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct Lsm9ds0Settings {
     i2c_dev_path: String,
+    instance: u8,
+    accel_range: AccelRange,
+    accel_data_rate: AccelDataRate,
+    // Hz
+    rate: f64,
+    attitude_time_constant: f64,
+    calibration: Option<String>,
+    // `[lsm9ds0_pub.magnetometer]`
+    magnetometer: IronCorrection,
+}
 
-    #[structopt(default_value = "ipc:///tmp/lsm9ds0.ipc")]
-    address: String,
+impl Default for Lsm9ds0Settings {
+    fn default() -> Lsm9ds0Settings {
+        Lsm9ds0Settings {
+            i2c_dev_path: String::from("/dev/i2c-1"),
+            instance: 0,
+            accel_range: AccelRange::default(),
+            accel_data_rate: AccelDataRate::default(),
+            rate: 100.0,
+            attitude_time_constant: 0.5,
+            calibration: None,
+            magnetometer: IronCorrection::default(),
+        }
+    }
 }
 
-struct Vector3 {
-    x: f32,
-    y: f32,
-    z: f32,
+// Reads the three little-endian axes starting at `start_addr` in one
+// transfer
+#[cfg(target_os = "linux")]
+fn read_raw(
+    i2c: &mut LinuxI2CDevice,
+    start_addr: u8,
+) -> Result<[i16; 3], LinuxI2CError> {
+    let bytes = i2c.smbus_read_i2c_block_data(start_addr | AUTO_INCREMENT, 6)?;
+
+    if bytes.len() < 6 {
+        return Err(LinuxI2CError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("read {} of 6 bytes at {:#04x}", bytes.len(), start_addr),
+        )));
+    }
+
+    let mut values = [0_i16; 3];
+
+    for (index, value) in values.iter_mut().enumerate() {
+        let low = bytes[2 * index] as u16;
+        let high = bytes[2 * index + 1] as u16;
+
+        *value = (low | (high << 8)) as i16;
+    }
+
+    Ok(values)
 }
 
+/// Accelerometer, magnetometer and gyroscope of the LSM9DS0, the gyroscope
+/// answers on a separate address
 #[cfg(target_os = "linux")]
-struct EmulatedGyro {
-    i2c: LinuxI2CDevice,
+struct Imu {
+    accel_mag: LinuxI2CDevice,
+    gyro: LinuxI2CDevice,
+    // mg/LSB of the configured range
+    accel_scale: f64,
 }
 
 #[cfg(target_os = "linux")]
-impl EmulatedGyro {
-    fn new(i2c_dev_path: String) -> Result<EmulatedGyro, LinuxI2CError> {
-        let mut i2c = LinuxI2CDevice::new(i2c_dev_path, ADDRESS_ACCELMAG)?;
+impl Imu {
+    fn new(
+        i2c_dev_path: String,
+        accel_range: AccelRange,
+        accel_data_rate: AccelDataRate,
+        sample_rate: f64,
+    ) -> Result<Imu, LinuxI2CError> {
+        let mut accel_mag =
+            LinuxI2CDevice::new(i2c_dev_path.clone(), ADDRESS_ACCELMAG)?;
+
+        // Enable accelerometer continous with all axes
+        accel_mag.smbus_write_byte_data(
+            REGISTER_CTRL_REG1_XM,
+            accel_data_rate.bits() | BLOCK_DATA_UPDATE_XM | 0b111,
+        )?;
+        // Temperature and high resolution magnetometer at 50 Hz
+        accel_mag.smbus_write_byte_data(REGISTER_CTRL_REG5_XM, 0b11110000)?;
+        // Enable magnetometer continous
+        accel_mag.smbus_write_byte_data(REGISTER_CTRL_REG7_XM, 0x00)?;
+
+        let mut mag_reg =
+            accel_mag.smbus_read_byte_data(REGISTER_CTRL_REG6_XM)?;
+        mag_reg &= !(0b01100000);
+        mag_reg |= MAGGAIN_2GAUSS;
+        accel_mag.smbus_write_byte_data(REGISTER_CTRL_REG6_XM, mag_reg)?;
+
+        let mut acc_reg =
+            accel_mag.smbus_read_byte_data(REGISTER_CTRL_REG2_XM)?;
+        acc_reg &= !(0b00111000);
+        acc_reg |= accel_range.bits();
+        accel_mag.smbus_write_byte_data(REGISTER_CTRL_REG2_XM, acc_reg)?;
+
+        let mut gyro = LinuxI2CDevice::new(i2c_dev_path, ADDRESS_GYRO)?;
+
+        // Normal mode with all axes enabled
+        gyro.smbus_write_byte_data(
+            REGISTER_CTRL_REG1_G,
+            range::gyro_data_rate_bits(sample_rate) | 0b1111,
+        )?;
+
+        let mut gyro_reg = gyro.smbus_read_byte_data(REGISTER_CTRL_REG4_G)?;
+        gyro_reg &= !(0b00110000);
+        gyro_reg |= GYRORANGE_500DPS | BLOCK_DATA_UPDATE_G;
+        gyro.smbus_write_byte_data(REGISTER_CTRL_REG4_G, gyro_reg)?;
+
+        Ok(Imu {
+            accel_mag: accel_mag,
+            gyro: gyro,
+            accel_scale: accel_range.scale(),
+        })
+    }
 
-        // Enable accelerometer continous
-        i2c.smbus_write_byte_data(REGISTER_CTRL_REG1_XM, 0x67)?;
-        i2c.smbus_write_byte_data(REGISTER_CTRL_REG5_XM, 0b11110000)?;
+    fn read_acc(&mut self) -> Result<Vector3, LinuxI2CError> {
+        let raw = read_raw(&mut self.accel_mag, REGISTER_OUT_X_L_A)?;
+        let scale = self.accel_scale;
+
+        Ok(Vector3 {
+            x: ((raw[0] as f64 * scale) / 1000.0) * GRAVITY,
+            y: ((raw[1] as f64 * scale) / 1000.0) * GRAVITY,
+            z: ((raw[2] as f64 * scale) / 1000.0) * GRAVITY,
+        })
+    }
 
-        let mut acc_reg = i2c.smbus_read_byte_data(REGISTER_CTRL_REG2_XM)?;
-        acc_reg &= !(0b00111000);
-        acc_reg |= ACCELRANGE_2G;
-        i2c.smbus_write_byte_data(REGISTER_CTRL_REG2_XM, acc_reg)?;
+    // Magnetic field in gauss
+    fn read_mag(&mut self) -> Result<Vector3, LinuxI2CError> {
+        let raw = read_raw(&mut self.accel_mag, REGISTER_OUT_X_L_M)?;
 
-        Ok(EmulatedGyro { i2c: i2c })
+        Ok(Vector3 {
+            x: (raw[0] as f64 * MAG_MGAUSS_LSB_2GAUSS) / 1000.0,
+            y: (raw[1] as f64 * MAG_MGAUSS_LSB_2GAUSS) / 1000.0,
+            z: (raw[2] as f64 * MAG_MGAUSS_LSB_2GAUSS) / 1000.0,
+        })
     }
 
-    fn read_raw(&mut self, start_addr: u8) -> Result<[i16; 3], LinuxI2CError> {
-        let mut values = [0_i16; 3];
+    // Rotation rates in deg/s
+    fn read_gyro(&mut self) -> Result<Vector3, LinuxI2CError> {
+        let raw = read_raw(&mut self.gyro, REGISTER_OUT_X_L_G)?;
 
-        let mut cur_addr = start_addr;
-        let mut index = 0;
+        Ok(Vector3 {
+            x: raw[0] as f64 * GYRO_DPS_DIGIT_500DPS,
+            y: raw[1] as f64 * GYRO_DPS_DIGIT_500DPS,
+            z: raw[2] as f64 * GYRO_DPS_DIGIT_500DPS,
+        })
+    }
+}
 
-        while cur_addr < start_addr + 6 {
-            let low = self.i2c.smbus_read_byte_data(cur_addr)? as u16;
-            let high = self.i2c.smbus_read_byte_data(cur_addr + 1)? as u16;
+#[cfg(target_os = "linux")]
+type Lsm9ds0Error = LinuxI2CError;
 
-            let unsigned_val = (low | (high << 8)) as u32;
+#[cfg(not(target_os = "linux"))]
+type Lsm9ds0Error = String;
 
-            values[index] = if unsigned_val > 32767 {
-                (unsigned_val as i32 - 65536) as i16
-            } else {
-                unsigned_val as i16
-            };
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
 
-            cur_addr += 2;
-            index += 1;
-        }
+// Interval between samples at `rate` Hz
+fn period(rate: f64) -> Duration {
+    let nanos = (1e9 / rate) as u64;
 
-        Ok(values)
-    }
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
 
-    fn read_acc(&mut self) -> Result<Vector3, LinuxI2CError> {
-        match self.read_raw(REGISTER_OUT_X_L_A) {
-            Ok(raw) => Ok(Vector3 {
-                x: ((raw[0] as f32 * ACCEL_MG_LSB_2G) / 1000.0) * GRAVITY,
-                y: ((raw[1] as f32 * ACCEL_MG_LSB_2G) / 1000.0) * GRAVITY,
-                z: ((raw[2] as f32 * ACCEL_MG_LSB_2G) / 1000.0) * GRAVITY,
-            }),
-            Err(err) => Err(err),
+struct Lsm9ds0 {
+    i2c_dev_path: String,
+    instance: u8,
+    #[cfg(target_os = "linux")]
+    accel_range: AccelRange,
+    #[cfg(target_os = "linux")]
+    accel_data_rate: AccelDataRate,
+    #[cfg(target_os = "linux")]
+    imu: Option<Imu>,
+    #[cfg(target_os = "linux")]
+    calibration: Option<Calibration>,
+    attitude: Attitude,
+    iron_correction: IronCorrection,
+    // Time step of the attitude before the second reading and of simulated
+    // samples
+    interval: Duration,
+    #[cfg(target_os = "linux")]
+    last_read: Option<Instant>,
+    simulation: Option<Simulation>,
+}
+
+impl Lsm9ds0 {
+    fn msg(&self, channel: &str, payload: Payload) -> PubMessage {
+        PubMessage {
+            channel: ChannelId::new(channel, self.instance),
+            payload: payload,
         }
     }
-}
 
-fn main() {
-    let opt = Opt::from_args();
+    // `rates` are in deg/s, `mag` is the uncorrected field in gauss and `dt`
+    // the time since the previous sample in seconds
+    fn sample(
+        &mut self,
+        acc: Vector3,
+        rates: Vector3,
+        mag: Vector3,
+        dt: f64,
+    ) -> Vec<PubMessage> {
+        // All readings of one sample are published as a single frame
+        let mut sample: Vec<PubMessage> = Vec::with_capacity(7);
+
+        // Carried on by the gyroscope while falling
+        let (roll, pitch) = self.attitude.update(&acc, &rates, dt);
+
+        let field = self.iron_correction.correct(&mag);
+        let heading = magnetometer::heading(&field, roll, pitch);
+
+        sample.push(self.msg(GYROSCOPE_X, Payload::Float(roll)));
+        sample.push(self.msg(GYROSCOPE_Y, Payload::Float(pitch)));
+        sample.push(self.msg(HEADING, Payload::Float(heading)));
+        sample.push(self.msg(MAGNETOMETER, Payload::Vector3(mag)));
+        sample.push(self.msg(IS_FALLING, Payload::Bool(acc.z < 6.0)));
+        sample.push(self.msg(ACCELEROMETER_Z, Payload::Float(acc.z)));
+        sample.push(self.msg(ACCELERATION, Payload::Vector3(acc)));
+
+        sample
+    }
+}
 
-    // Obtain config values
-    let i2c_dev_path = opt.i2c_dev_path;
+impl Sensor for Lsm9ds0 {
+    type Error = Lsm9ds0Error;
 
-    // Create nanomsg publisher socket
-    let mut socket =
-        Socket::new(Protocol::Pub).expect("could not create socket");
-    socket
-        .bind(opt.address.as_str())
-        .expect("socket bind failed");
+    #[cfg(target_os = "linux")]
+    fn init(&mut self) -> Result<(), LinuxI2CError> {
+        let imu = Imu::new(
+            self.i2c_dev_path.clone(),
+            self.accel_range,
+            self.accel_data_rate,
+            1.0 / seconds(self.interval),
+        )?;
+        self.imu = Some(imu);
+
+        Ok(())
+    }
 
-    let sleep_duration = Duration::from_millis(10);
+    #[cfg(not(target_os = "linux"))]
+    fn init(&mut self) -> Result<(), String> {
+        Err(format!("{} requires Linux", self.i2c_dev_path))
+    }
 
     #[cfg(target_os = "linux")]
-    fn publish_values(
-        mut socket: Socket,
-        sleep_duration: Duration,
-        i2c_dev_path: String,
-    ) {
-        let mut roll_msg = PubMessage {
-            pub_type: PubType::GyroscopeX,
-            integral: 0,
-            decimal: 0.0,
-        };
+    fn read(&mut self) -> Result<Vec<PubMessage>, LinuxI2CError> {
+        let (acc, rates, mag) = {
+            let imu = self.imu.as_mut().expect("sensor not initialized");
 
-        let mut pitch_msg = PubMessage {
-            pub_type: PubType::GyroscopeY,
-            integral: 0,
-            decimal: 0.0,
+            (imu.read_acc()?, imu.read_gyro()?, imu.read_mag()?)
         };
 
-        let mut is_falling_msg = PubMessage {
-            pub_type: PubType::IsFalling,
-            integral: 0,
-            decimal: 0.0,
+        let (acc, rates) = match self.calibration {
+            Some(ref calibration) => {
+                (calibration.accel(&acc), calibration.gyro(&rates))
+            }
+            None => (acc, rates),
         };
 
-        let mut accelerometer_z_msg = PubMessage {
-            pub_type: PubType::AccelerometerZ,
-            integral: 0,
-            decimal: 0.0,
-        };
+        let now = Instant::now();
+        let dt = seconds(
+            self.last_read
+                .map(|last_read| now.duration_since(last_read))
+                .unwrap_or(self.interval),
+        );
+        self.last_read = Some(now);
 
-        if let Ok(mut emulated_gyro) = EmulatedGyro::new(i2c_dev_path) {
-            loop {
-                let acc = emulated_gyro.read_acc().unwrap();
-
-                //                    y
-                //      roll = atan2(---)
-                //                    z
-
-                let mut roll = acc.y.atan2(acc.z);
-
-                //                                 -x
-                //      pitch = atan(-------------------------------)
-                //                    y * sin(roll) + z * cos(roll)
-                //
-                let mut pitch = if acc.y * roll.sin() + acc.z * roll.cos()
-                    == 0.0
-                {
-                    if acc.x > 0.0 {
-                        PI / 2.0
-                    } else {
-                        -PI / 2.0
-                    }
-                } else {
-                    (-acc.x / (acc.y * roll.sin() + acc.z * roll.cos())).atan()
-                };
-
-                roll = roll * 180.0 / PI;
-                pitch = pitch * 180.0 / PI;
-
-                // Calculations are inaccurate if falling
-                if acc.z > 8.0 {
-                    roll_msg = fill_message_decimal(roll, roll_msg);
-                    pitch_msg = fill_message_decimal(pitch, pitch_msg);
-
-                    publish(&mut socket, &roll_msg);
-                    publish(&mut socket, &pitch_msg);
-                }
+        Ok(self.sample(acc, rates, mag, dt))
+    }
 
-                is_falling_msg = fill_message_integral(
-                    if acc.z < 6.0 { 1 } else { 0 },
-                    is_falling_msg,
-                );
+    #[cfg(not(target_os = "linux"))]
+    fn read(&mut self) -> Result<Vec<PubMessage>, String> {
+        Err(format!("{} requires Linux", self.i2c_dev_path))
+    }
 
-                publish(&mut socket, &is_falling_msg);
+    fn channels(&self) -> Vec<(ChannelId, PayloadKind)> {
+        [
+            (GYROSCOPE_X, PayloadKind::Float),
+            (GYROSCOPE_Y, PayloadKind::Float),
+            (IS_FALLING, PayloadKind::Bool),
+            (ACCELEROMETER_Z, PayloadKind::Float),
+            (ACCELERATION, PayloadKind::Vector3),
+            (MAGNETOMETER, PayloadKind::Vector3),
+            (HEADING, PayloadKind::Float),
+        ].iter()
+            .map(|&(name, kind)| (ChannelId::new(name, self.instance), kind))
+            .collect()
+    }
 
-                accelerometer_z_msg =
-                    fill_message_decimal(acc.z, accelerometer_z_msg);
+    fn simulate(&mut self) -> Vec<PubMessage> {
+        let acc_z = match self.simulation {
+            Some(ref mut simulation) => {
+                let time = simulation.tick();
 
-                publish(&mut socket, &accelerometer_z_msg);
+                // Acceleration measured during the simulated drop
+                match simulation.falling(time) {
+                    Some(true) => simulation.noisy(0.0),
+                    Some(false) => simulation.noisy(GRAVITY),
+                    None => simulation.value(time),
+                }
+            }
+            // Only the roll is random, a random IsFalling fires the valves
+            None => {
+                let payload = random_payload(PayloadKind::Float, &BETWEEN);
 
-                sleep(sleep_duration);
+                return vec![self.msg(GYROSCOPE_X, payload)];
             }
-        } else {
-            publish_random_values(socket, roll_msg, sleep_duration, *BETWEEN);
+        };
+
+        let dt = seconds(self.interval);
+
+        // Simulated without rotation, facing north
+        self.sample(
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: acc_z,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.2,
+                y: 0.0,
+                z: -0.4,
+            },
+            dt,
+        )
+    }
+}
+
+// Readings at rest averaged over `CALIBRATION_SAMPLES`, acceleration and
+// rotation rates
+#[cfg(target_os = "linux")]
+fn average(
+    imu: &mut Imu,
+    interval: Duration,
+) -> Result<(Vector3, Vector3), LinuxI2CError> {
+    let mut acc = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    let mut rates = acc;
+
+    for _ in 0..CALIBRATION_SAMPLES {
+        let sample_acc = imu.read_acc()?;
+        let sample_rates = imu.read_gyro()?;
+
+        acc.x += sample_acc.x;
+        acc.y += sample_acc.y;
+        acc.z += sample_acc.z;
+        rates.x += sample_rates.x;
+        rates.y += sample_rates.y;
+        rates.z += sample_rates.z;
+
+        thread::sleep(interval);
+    }
+
+    let samples = CALIBRATION_SAMPLES as f64;
+
+    Ok((
+        Vector3 {
+            x: acc.x / samples,
+            y: acc.y / samples,
+            z: acc.z / samples,
+        },
+        Vector3 {
+            x: rates.x / samples,
+            y: rates.y / samples,
+            z: rates.z / samples,
+        },
+    ))
+}
+
+// Guides through the six orientations, the gyroscope bias is averaged over
+// all of them
+#[cfg(target_os = "linux")]
+fn calibrate(
+    lsm9ds0: &Lsm9ds0Settings,
+    interval: Duration,
+) -> Result<Calibration, String> {
+    let mut imu = Imu::new(
+        lsm9ds0.i2c_dev_path.clone(),
+        lsm9ds0.accel_range,
+        lsm9ds0.accel_data_rate,
+        lsm9ds0.rate,
+    ).map_err(|err| format!("{} ({})", lsm9ds0.i2c_dev_path, err))?;
+
+    let zero = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    let mut accel = [zero; 6];
+    let mut gyro_bias = zero;
+
+    let mut index = 0;
+
+    while index < ORIENTATIONS.len() {
+        let (name, up_axis, sign) = ORIENTATIONS[index];
+
+        println!("Hold the sensor still with its {} and press enter", name);
+
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map_err(|err| err.to_string())?;
+
+        let (acc, rates) =
+            average(&mut imu, interval).map_err(|err| err.to_string())?;
+
+        // Gravity has to act along the expected axis
+        let up = axis(&acc, up_axis) * sign;
+        let aligned = (0..3)
+            .filter(|&other| other != up_axis)
+            .all(|other| axis(&acc, other).abs() < up);
+
+        if !aligned {
+            println!(
+                "Measured {:?}, which is not the {}, try again",
+                acc, name
+            );
+            continue;
         }
+
+        accel[index] = acc;
+        gyro_bias.x += rates.x / ORIENTATIONS.len() as f64;
+        gyro_bias.y += rates.y / ORIENTATIONS.len() as f64;
+        gyro_bias.z += rates.z / ORIENTATIONS.len() as f64;
+
+        index += 1;
     }
 
-    #[cfg(not(target_os = "linux"))]
-    fn publish_values(
-        socket: Socket,
-        sleep_duration: Duration,
-        _i2c_dev_path: String,
+    Ok(Calibration::fit(
+        &accel,
+        gyro_bias,
+        lsm9ds0.accel_range.g(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn calibrate(
+    lsm9ds0: &Lsm9ds0Settings,
+    _interval: Duration,
+) -> Result<Calibration, String> {
+    Err(format!("{} requires Linux", lsm9ds0.i2c_dev_path))
+}
+
+// Reports the active calibration, a configured file has to load and match
+// the accelerometer range
+fn load_calibration(
+    lsm9ds0: &Lsm9ds0Settings,
+    config: Option<&str>,
+) -> Option<Calibration> {
+    let path = match existing_calibration(
+        lsm9ds0.calibration.as_ref(),
+        config,
+        DEFAULT_CALIBRATION,
     ) {
-        let msg = PubMessage {
-            pub_type: PubType::GyroscopeX,
-            integral: 0,
-            decimal: 0.0,
-        };
+        Some(path) => path,
+        None => {
+            println!("No calibration, readings use the nominal sensitivity");
+            return None;
+        }
+    };
+
+    let calibration: Calibration =
+        read_calibration(&path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+
+    // Offset and scale are in m/s², but the zero-g bias and the sensitivity
+    // error differ between ranges, so they only correct the range they were
+    // recorded at
+    if calibration.accel_range != lsm9ds0.accel_range.g() {
+        eprintln!(
+            "Calibration {} was recorded at ±{} g, the accelerometer runs \
+             at ±{} g, calibrate again with --calibrate",
+            path,
+            calibration.accel_range,
+            lsm9ds0.accel_range.g()
+        );
+        process::exit(1);
+    }
+
+    println!(
+        "Using calibration {} recorded at ±{} g",
+        path, calibration.accel_range
+    );
+
+    Some(calibration)
+}
+
+fn main() {
+    let opt = Opt::from_args();
 
-        publish_random_values(socket, msg, sleep_duration, *BETWEEN);
+    let settings =
+        Settings::load(opt.config.as_ref().map(String::as_str), false)
+            .expect("could not load configuration");
+    let section = opt.publisher_id
+        .clone()
+        .unwrap_or_else(|| NAME.to_string());
+
+    let mut publisher: PublisherSettings = settings
+        .section(&section)
+        .expect("invalid publisher configuration");
+    let mut lsm9ds0: Lsm9ds0Settings = settings
+        .section(&section)
+        .expect("invalid lsm9ds0 configuration");
+
+    layer(&mut lsm9ds0.i2c_dev_path, opt.i2c_dev_path);
+    layer(&mut lsm9ds0.instance, opt.instance);
+    layer(&mut lsm9ds0.accel_range, opt.accel_range);
+    layer(&mut lsm9ds0.accel_data_rate, opt.accel_data_rate);
+    layer(&mut lsm9ds0.rate, opt.rate);
+    layer(
+        &mut lsm9ds0.attitude_time_constant,
+        opt.attitude_time_constant,
+    );
+    layer(&mut lsm9ds0.calibration, opt.calibration.map(Some));
+    layer(&mut publisher.address, opt.address.map(Some));
+    layer(&mut publisher.publisher_id, opt.publisher_id.map(Some));
+    layer(&mut publisher.codec, opt.codec);
+    layer(&mut publisher.key, opt.key.map(Some));
+    layer(&mut publisher.simulate, opt.simulate.map(Some));
+    layer(&mut publisher.noise, opt.noise);
+    layer(&mut publisher.seed, opt.seed);
+    layer(&mut publisher.on_error, opt.on_error);
+    layer(&mut publisher.overrun, opt.overrun);
+
+    if !(lsm9ds0.rate > 0.0 && lsm9ds0.rate.is_finite()) {
+        eprintln!("rate must be positive, got {}", lsm9ds0.rate);
+        process::exit(1);
     }
 
-    publish_values(socket, sleep_duration, i2c_dev_path);
+    let time_constant = lsm9ds0.attitude_time_constant;
+
+    if !(time_constant > 0.0 && time_constant.is_finite()) {
+        eprintln!(
+            "attitude_time_constant must be positive, got {}",
+            time_constant
+        );
+        process::exit(1);
+    }
+
+    if lsm9ds0.rate > lsm9ds0.accel_data_rate.hz() {
+        eprintln!(
+            "Sampling at {} Hz repeats readings of the accelerometer \
+             running at {} Hz",
+            lsm9ds0.rate,
+            lsm9ds0.accel_data_rate.hz()
+        );
+    }
+
+    let interval = period(lsm9ds0.rate);
+
+    if opt.calibrate {
+        let path = calibration_path(
+            lsm9ds0.calibration.as_ref(),
+            opt.config.as_ref().map(String::as_str),
+            DEFAULT_CALIBRATION,
+        );
+
+        let result = calibrate(&lsm9ds0, interval)
+            .and_then(|calibration| write_calibration(&calibration, &path));
+
+        match result {
+            Ok(_) => println!("Wrote calibration to {}", path),
+            Err(err) => {
+                eprintln!("Calibration failed ({})", err);
+                process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    // Only applied to readings of the hardware
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    let calibration =
+        load_calibration(&lsm9ds0, opt.config.as_ref().map(String::as_str));
+
+    let sensor = Lsm9ds0 {
+        i2c_dev_path: lsm9ds0.i2c_dev_path,
+        instance: lsm9ds0.instance,
+        #[cfg(target_os = "linux")]
+        accel_range: lsm9ds0.accel_range,
+        #[cfg(target_os = "linux")]
+        accel_data_rate: lsm9ds0.accel_data_rate,
+        #[cfg(target_os = "linux")]
+        imu: None,
+        #[cfg(target_os = "linux")]
+        calibration: calibration,
+        attitude: Attitude::new(lsm9ds0.attitude_time_constant),
+        iron_correction: lsm9ds0.magnetometer,
+        interval: interval,
+        #[cfg(target_os = "linux")]
+        last_read: None,
+        simulation: publisher.simulation(interval),
+    };
+
+    let mut runner = Runner::new(
+        sensor,
+        publisher.runner_config(&section, DEFAULT_ADDRESS, interval),
+    ).expect("socket bind failed");
+
+    if let Err(err) = runner.run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+// Full scales in g with their sensitivity in mg/LSB, the index is written
+// to the AFS bits
+const ACCEL_RANGES: [(u8, f64); 5] = [
+    (2, 0.061),
+    (4, 0.122),
+    (6, 0.183),
+    (8, 0.244),
+    (16, 0.732),
+];
+
+// Output data rates in Hz, the index plus one is written to the AODR bits
+const ACCEL_DATA_RATES: [f64; 10] = [
+    3.125, 6.25, 12.5, 25.0, 50.0, 100.0, 200.0, 400.0, 800.0, 1600.0,
+];
+
+// Output data rates of the gyroscope in Hz, the index is written to the DR
+// bits
+#[cfg(target_os = "linux")]
+const GYRO_DATA_RATES: [f64; 4] = [95.0, 190.0, 380.0, 760.0];
+
+/// Full scale of the accelerometer, e.g. `16` or `16g` for ±16 g
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AccelRange {
+    index: usize,
+}
+
+impl AccelRange {
+    /// AFS bits of CTRL_REG2_XM
+    #[cfg(target_os = "linux")]
+    pub fn bits(&self) -> u8 {
+        (self.index as u8) << 3
+    }
+
+    /// Sensitivity in mg/LSB
+    #[cfg(target_os = "linux")]
+    pub fn scale(&self) -> f64 {
+        ACCEL_RANGES[self.index].1
+    }
+
+    pub fn g(&self) -> u8 {
+        ACCEL_RANGES[self.index].0
+    }
+
+    fn from_g(g: u8) -> Result<AccelRange, String> {
+        ACCEL_RANGES
+            .iter()
+            .position(|&(range, _)| range == g)
+            .map(|index| AccelRange { index: index })
+            .ok_or_else(|| {
+                format!("unsupported accelerometer range ±{} g", g)
+            })
+    }
+}
+
+impl Default for AccelRange {
+    fn default() -> AccelRange {
+        AccelRange { index: 0 }
+    }
+}
+
+impl FromStr for AccelRange {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<AccelRange, String> {
+        let g = spec.trim_right_matches('g');
+
+        g.parse()
+            .map_err(|_| format!("invalid accelerometer range '{}'", spec))
+            .and_then(AccelRange::from_g)
+    }
+}
+
+impl<'de> Deserialize<'de> for AccelRange {
+    fn deserialize<D>(deserializer: D) -> Result<AccelRange, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let g = u8::deserialize(deserializer)?;
+
+        AccelRange::from_g(g).map_err(de::Error::custom)
+    }
+}
+
+/// Output data rate of the accelerometer in Hz, from 3.125 to 1600
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AccelDataRate {
+    index: usize,
+}
+
+impl AccelDataRate {
+    /// AODR bits of CTRL_REG1_XM
+    #[cfg(target_os = "linux")]
+    pub fn bits(&self) -> u8 {
+        (self.index as u8 + 1) << 4
+    }
+
+    pub fn hz(&self) -> f64 {
+        ACCEL_DATA_RATES[self.index]
+    }
+
+    fn from_hz(hz: f64) -> Result<AccelDataRate, String> {
+        ACCEL_DATA_RATES
+            .iter()
+            .position(|&rate| (rate - hz).abs() < 1e-9)
+            .map(|index| AccelDataRate { index: index })
+            .ok_or_else(|| {
+                format!(
+                    "unsupported accelerometer data rate {} Hz, one of {:?}",
+                    hz, ACCEL_DATA_RATES
+                )
+            })
+    }
+}
+
+impl Default for AccelDataRate {
+    fn default() -> AccelDataRate {
+        // 100 Hz
+        AccelDataRate { index: 5 }
+    }
+}
+
+impl FromStr for AccelDataRate {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<AccelDataRate, String> {
+        spec.parse()
+            .map_err(|_| format!("invalid accelerometer data rate '{}'", spec))
+            .and_then(AccelDataRate::from_hz)
+    }
+}
+
+impl<'de> Deserialize<'de> for AccelDataRate {
+    fn deserialize<D>(deserializer: D) -> Result<AccelDataRate, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hz = f64::deserialize(deserializer)?;
+
+        AccelDataRate::from_hz(hz).map_err(de::Error::custom)
+    }
+}
+
+/// DR bits of CTRL_REG1_G for the slowest gyroscope data rate that keeps up
+/// with the sample rate
+#[cfg(target_os = "linux")]
+pub fn gyro_data_rate_bits(sample_rate: f64) -> u8 {
+    let index = GYRO_DATA_RATES
+        .iter()
+        .position(|&rate| rate >= sample_rate)
+        .unwrap_or(GYRO_DATA_RATES.len() - 1);
+
+    (index as u8) << 6
+}
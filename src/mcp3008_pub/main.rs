@@ -1,87 +1,249 @@
-extern crate nanomsg;
 extern crate rand;
 #[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate structopt;
 
 extern crate mcp3008;
 
 extern crate shared;
 
-use std::thread::sleep;
+use std::process;
 use std::time::Duration;
 
-use nanomsg::{Protocol, Socket};
-
-use mcp3008::Mcp3008;
+use mcp3008::{Mcp3008, Mcp3008Error};
 
 use rand::distributions::Range;
 
 use structopt::StructOpt;
 
-use shared::types::{str_to_pub_type, PubMessage};
-use shared::utils::{fill_message_integral, publish, publish_random_values};
+use shared::codec::CodecKind;
+use shared::sensor::{random_payload, ErrorPolicy, Runner, Sensor};
+use shared::settings::{layer, PublisherSettings, Settings};
+use shared::simulation::{Simulation, Waveform};
+use shared::ticker::OverrunPolicy;
+use shared::types::{ChannelId, Payload, PayloadKind, PubMessage};
+
+const NAME: &'static str = "mcp3008_pub";
+
+const DEFAULT_ADDRESS: &'static str = "ipc:///tmp/mcp3008_1.ipc";
 
+// Flags override the configuration file and environment
 #[derive(StructOpt, Debug)]
 #[structopt(name = "mcp3008_pub")]
 struct Opt {
+    /// Configuration file, defaults to the rig config if present
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+
+    /// MCP3008 input the sensor is connected to, 0 to 7
     #[structopt(short = "a", long = "adc")]
-    adc: u8,
+    adc: Option<u8>,
 
-    #[structopt(short = "d", long = "delay", default_value = "1")]
-    delay: u64,
+    /// Milliseconds between two readings
+    #[structopt(short = "d", long = "delay")]
+    delay: Option<u64>,
+
+    /// Channel the readings are published on
+    #[structopt(short = "t", long = "channel")]
+    channel: Option<String>,
+
+    /// Instance of the channel, defaults to 0
+    #[structopt(short = "i", long = "instance")]
+    instance: Option<u8>,
+
+    /// SPI device of the MCP3008
+    #[structopt(long = "spi-dev-path")]
+    spi_dev_path: Option<String>,
+
+    /// Samples per frame
+    #[structopt(short = "b", long = "batch-size")]
+    batch_size: Option<usize>,
+
+    /// Maximum age in ms of a queued sample before the batch is published
+    #[structopt(long = "flush-interval")]
+    flush_interval: Option<u64>,
+
+    /// Frame encoding: bincode, cbor, msgpack or json
+    #[structopt(long = "codec")]
+    codec: Option<CodecKind>,
 
-    #[structopt(short = "t", long = "pub-type")]
-    pub_type: String,
+    /// Also selects the section of the configuration file, defaults to
+    /// mcp3008_pub
+    #[structopt(long = "publisher-id")]
+    publisher_id: Option<String>,
 
-    #[structopt(long = "spi-dev-path", default_value = "/dev/spidev0.0")]
+    /// Pre-shared key frames are signed with, required on TCP transports
+    #[structopt(long = "key")]
+    key: Option<String>,
+
+    /// Simulated waveform without hardware, e.g. free-fall:80,5,980.665
+    #[structopt(long = "simulate")]
+    simulate: Option<Waveform>,
+
+    /// Standard deviation of the noise added to simulated values
+    #[structopt(long = "noise")]
+    noise: Option<f64>,
+
+    /// Seed of the simulation noise
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Handling of failed reads and writes: retry:ATTEMPTS,BACKOFF_MS, skip
+    /// or escalate:FAILURES
+    #[structopt(long = "on-error")]
+    on_error: Option<ErrorPolicy>,
+
+    /// Handling of reads that overrun their period: skip or catch-up
+    #[structopt(long = "overrun")]
+    overrun: Option<OverrunPolicy>,
+
+    /// Address the frames are published on
+    address: Option<String>,
+}
+
+/// `[mcp3008_pub]` section of the configuration file, `adc` and `channel`
+/// have no defaults
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct Mcp3008Settings {
+    adc: Option<u8>,
+    delay: u64,
+    channel: Option<String>,
+    instance: u8,
     spi_dev_path: String,
+    batch_size: usize,
+    flush_interval: u64,
+}
+
+impl Default for Mcp3008Settings {
+    fn default() -> Mcp3008Settings {
+        Mcp3008Settings {
+            adc: None,
+            delay: 1,
+            channel: None,
+            instance: 0,
+            spi_dev_path: String::from("/dev/spidev0.0"),
+            batch_size: 1,
+            flush_interval: 10,
+        }
+    }
+}
 
-    #[structopt(default_value = "ipc:///tmp/mcp3008_1.ipc")]
-    address: String,
+struct Mcp3008Sensor {
+    spi_dev_path: String,
+    adc: u8,
+    mcp3008: Option<Mcp3008>,
+    simulation: Option<Simulation>,
+    // Instead of creating new messages modify an existing one
+    msg: PubMessage,
 }
 
-fn main() {
-    let opt = Opt::from_args();
+impl Sensor for Mcp3008Sensor {
+    type Error = Mcp3008Error;
 
-    let pub_type =
-        str_to_pub_type(&opt.pub_type).expect("invalid publisher type");
+    fn init(&mut self) -> Result<(), Mcp3008Error> {
+        self.mcp3008 = Some(Mcp3008::new(&self.spi_dev_path)?);
 
-    // Create nanomsg publisher socket
-    let mut socket =
-        Socket::new(Protocol::Pub).expect("could not create socket");
-    socket
-        .bind(opt.address.as_str())
-        .expect("socket bind failed");
+        Ok(())
+    }
 
-    // Instead of creating new messages modify an existing one
-    let mut msg = PubMessage {
-        pub_type: pub_type,
-        integral: 0,
-        decimal: 0.0,
-    };
+    fn read(&mut self) -> Result<Vec<PubMessage>, Mcp3008Error> {
+        let mcp3008 = self.mcp3008.as_mut().expect("sensor not initialized");
 
-    // std::thread::yield_now() can not be used to prevent excessive CPU
-    // usage -> sleep 1ms instead
-    let sleep_duration = Duration::from_millis(opt.delay);
+        let value = mcp3008.read_adc(self.adc)?;
+        self.msg.payload = Payload::Integer(value as i64);
 
-    if let Ok(mut mcp3008) = Mcp3008::new(&opt.spi_dev_path) {
-        loop {
-            if let Ok(value) = mcp3008.read_adc(opt.adc) {
-                msg = fill_message_integral(value as i16, msg);
-            } else {
-                panic!("could not read from Mcp3008")
+        Ok(vec![self.msg.clone()])
+    }
+
+    fn channels(&self) -> Vec<(ChannelId, PayloadKind)> {
+        vec![(self.msg.channel.clone(), PayloadKind::Integer)]
+    }
+
+    fn simulate(&mut self) -> Vec<PubMessage> {
+        self.msg.payload = match self.simulation {
+            Some(ref mut simulation) => {
+                simulation.payload(PayloadKind::Integer)
+            }
+            None => {
+                random_payload(PayloadKind::Integer, &Range::new(0.0, 1024.0))
             }
+        };
 
-            publish(&mut socket, &msg);
+        vec![self.msg.clone()]
+    }
+}
 
-            sleep(sleep_duration);
-        }
-    } else {
-        publish_random_values(
-            socket,
-            msg,
-            sleep_duration,
-            Range::new(0.0, 1024.0),
+fn main() {
+    let opt = Opt::from_args();
+
+    let settings =
+        Settings::load(opt.config.as_ref().map(String::as_str), false)
+            .expect("could not load configuration");
+    let section = opt.publisher_id
+        .clone()
+        .unwrap_or_else(|| NAME.to_string());
+
+    let mut publisher: PublisherSettings = settings
+        .section(&section)
+        .expect("invalid publisher configuration");
+    let mut mcp3008: Mcp3008Settings = settings
+        .section(&section)
+        .expect("invalid mcp3008 configuration");
+
+    layer(&mut mcp3008.adc, opt.adc.map(Some));
+    layer(&mut mcp3008.delay, opt.delay);
+    layer(&mut mcp3008.channel, opt.channel.map(Some));
+    layer(&mut mcp3008.instance, opt.instance);
+    layer(&mut mcp3008.spi_dev_path, opt.spi_dev_path);
+    layer(&mut mcp3008.batch_size, opt.batch_size);
+    layer(&mut mcp3008.flush_interval, opt.flush_interval);
+    layer(&mut publisher.address, opt.address.map(Some));
+    layer(&mut publisher.publisher_id, opt.publisher_id.map(Some));
+    layer(&mut publisher.codec, opt.codec);
+    layer(&mut publisher.key, opt.key.map(Some));
+    layer(&mut publisher.simulate, opt.simulate.map(Some));
+    layer(&mut publisher.noise, opt.noise);
+    layer(&mut publisher.seed, opt.seed);
+    layer(&mut publisher.on_error, opt.on_error);
+    layer(&mut publisher.overrun, opt.overrun);
+
+    let adc = mcp3008.adc.unwrap_or_else(|| {
+        eprintln!("No adc configured, set adc in [{}] or pass --adc", section);
+        process::exit(1);
+    });
+
+    let channel = mcp3008.channel.clone().unwrap_or_else(|| {
+        eprintln!(
+            "No channel configured, set channel in [{}] or pass --channel",
+            section
         );
+        process::exit(1);
+    });
+
+    let interval = Duration::from_millis(mcp3008.delay);
+
+    let sensor = Mcp3008Sensor {
+        spi_dev_path: mcp3008.spi_dev_path.clone(),
+        adc: adc,
+        mcp3008: None,
+        simulation: publisher.simulation(interval),
+        msg: PubMessage {
+            channel: ChannelId::new(&channel, mcp3008.instance),
+            payload: Payload::Integer(0),
+        },
+    };
+
+    let mut config =
+        publisher.runner_config(&section, DEFAULT_ADDRESS, interval);
+    config.batch_size = mcp3008.batch_size;
+    config.flush_interval = Duration::from_millis(mcp3008.flush_interval);
+
+    let mut runner = Runner::new(sensor, config).expect("socket bind failed");
+
+    if let Err(err) = runner.run() {
+        eprintln!("{}", err);
+        process::exit(1);
     }
 }
@@ -0,0 +1,278 @@
+use config::{Config, ConfigError};
+
+use filter::FilterSpec;
+use types::{ChannelAnnouncement, ChannelId, PayloadKind, ACCELERATION,
+            ACCELEROMETER_Z, GYROSCOPE_X, GYROSCOPE_Y, HEADING, IS_FALLING,
+            LONG_DISTANCE_SENSOR, MAGNETOMETER, POWER_BUTTON,
+            PRESSURE_SENSOR_PRESSURE, PRESSURE_SENSOR_TEMPERATURE};
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ValueRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Channel as declared in the config file or announced by a publisher
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    #[serde(default)]
+    pub instance: u8,
+    #[serde(default)]
+    pub unit: String,
+    #[serde(default)]
+    pub range: Option<ValueRange>,
+    #[serde(default)]
+    pub description: String,
+    // Short human readable name for plots and gauges
+    #[serde(default)]
+    pub label: Option<String>,
+    // Taken from the announcement unless declared
+    #[serde(default)]
+    pub kind: Option<PayloadKind>,
+    // Nominal rate in Hz, taken from the announcement unless declared
+    #[serde(default)]
+    pub rate: Option<f64>,
+    // Id of the publisher that announced the channel, set at runtime
+    #[serde(default)]
+    pub publisher: Option<String>,
+    // Age in ms after which cached readings are stale, the hub default
+    // applies if unset
+    #[serde(default)]
+    pub max_age_ms: Option<u64>,
+    // Applied by the hub to every reading of the channel
+    #[serde(default)]
+    pub filter: Option<FilterSpec>,
+}
+
+/// Everything mission control needs to present a channel
+#[derive(Serialize, Clone, Debug)]
+pub struct ChannelSchema {
+    pub name: String,
+    pub instance: u8,
+    pub label: String,
+    pub description: String,
+    pub unit: String,
+    pub range: Option<ValueRange>,
+    pub kind: Option<PayloadKind>,
+    pub rate: Option<f64>,
+    pub publisher: Option<String>,
+}
+
+impl Channel {
+    pub fn new(name: &str, instance: u8) -> Channel {
+        Channel {
+            name: name.to_string(),
+            instance: instance,
+            unit: String::new(),
+            range: None,
+            description: String::new(),
+            label: None,
+            kind: None,
+            rate: None,
+            publisher: None,
+            max_age_ms: None,
+            filter: None,
+        }
+    }
+
+    pub fn id(&self) -> ChannelId {
+        ChannelId::new(&self.name, self.instance)
+    }
+
+    fn is(&self, id: &ChannelId) -> bool {
+        self.name == id.name && self.instance == id.instance
+    }
+
+    pub fn schema(&self) -> ChannelSchema {
+        let label = match self.label {
+            Some(ref label) => label.clone(),
+            None if self.instance == 0 => self.name.clone(),
+            None => self.id().to_string(),
+        };
+
+        ChannelSchema {
+            name: self.name.clone(),
+            instance: self.instance,
+            label: label,
+            description: self.description.clone(),
+            unit: self.unit.clone(),
+            range: self.range.clone(),
+            kind: self.kind,
+            rate: self.rate,
+            publisher: self.publisher.clone(),
+        }
+    }
+
+    fn announced(
+        &mut self,
+        publisher_id: &str,
+        announcement: &ChannelAnnouncement,
+    ) {
+        self.publisher = Some(publisher_id.to_string());
+
+        if self.kind.is_none() {
+            self.kind = Some(announcement.kind);
+        }
+        if self.rate.is_none() {
+            self.rate = Some(announcement.rate);
+        }
+    }
+}
+
+// Shorthand for the built-in channel table
+fn builtin(
+    name: &str,
+    kind: PayloadKind,
+    unit: &str,
+    range: Option<(f64, f64)>,
+    description: &str,
+) -> Channel {
+    Channel {
+        kind: Some(kind),
+        unit: unit.to_string(),
+        range: range.map(|(min, max)| ValueRange { min: min, max: max }),
+        description: description.to_string(),
+        ..Channel::new(name, 0)
+    }
+}
+
+/// Channels the hub knows about, keyed by name and instance
+#[derive(Serialize, Clone, Debug)]
+pub struct Registry {
+    channels: Vec<Channel>,
+}
+
+impl Registry {
+    pub fn new(channels: Vec<Channel>) -> Registry {
+        Registry { channels: channels }
+    }
+
+    /// Channels of the bundled publishers, used if the config declares none
+    pub fn builtin() -> Registry {
+        Registry::new(vec![
+            builtin(
+                PRESSURE_SENSOR_TEMPERATURE,
+                PayloadKind::Float,
+                "degC",
+                None,
+                "Pressure sensor temperature",
+            ),
+            builtin(
+                PRESSURE_SENSOR_PRESSURE,
+                PayloadKind::Float,
+                "hPa",
+                None,
+                "Ambient pressure",
+            ),
+            builtin(
+                LONG_DISTANCE_SENSOR,
+                PayloadKind::Float,
+                "cm",
+                Some((0.0, 80.0)),
+                "Altitude above ground",
+            ),
+            builtin(
+                ACCELEROMETER_Z,
+                PayloadKind::Float,
+                "m/s^2",
+                Some((-19.6, 19.6)),
+                "Vertical acceleration",
+            ),
+            builtin(
+                GYROSCOPE_X,
+                PayloadKind::Float,
+                "deg",
+                Some((-180.0, 180.0)),
+                "Roll",
+            ),
+            builtin(
+                GYROSCOPE_Y,
+                PayloadKind::Float,
+                "deg",
+                Some((-180.0, 180.0)),
+                "Pitch",
+            ),
+            builtin(
+                IS_FALLING,
+                PayloadKind::Bool,
+                "",
+                None,
+                "Free fall detected",
+            ),
+            builtin(
+                POWER_BUTTON,
+                PayloadKind::Bool,
+                "",
+                None,
+                "Power button pressed",
+            ),
+            builtin(
+                ACCELERATION,
+                PayloadKind::Vector3,
+                "m/s^2",
+                Some((-19.6, 19.6)),
+                "Acceleration vector",
+            ),
+            builtin(
+                MAGNETOMETER,
+                PayloadKind::Vector3,
+                "gauss",
+                Some((-2.0, 2.0)),
+                "Magnetic field, uncorrected",
+            ),
+            builtin(
+                HEADING,
+                PayloadKind::Float,
+                "deg",
+                Some((0.0, 360.0)),
+                "Tilt-compensated heading",
+            ),
+        ])
+    }
+
+    /// Reads the `channels` array, falling back to the built-in channels
+    pub fn from_config(config: &Config) -> Result<Registry, ConfigError> {
+        match config.get::<Vec<Channel>>("channels") {
+            Ok(channels) => Ok(Registry::new(channels)),
+            Err(ConfigError::NotFound(_)) => Ok(Registry::builtin()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    pub fn lookup(&self, id: &ChannelId) -> Option<&Channel> {
+        self.channels.iter().find(|channel| channel.is(id))
+    }
+
+    pub fn schema(&self) -> Vec<ChannelSchema> {
+        self.channels.iter().map(|channel| channel.schema()).collect()
+    }
+
+    /// Assigns a channel to the announcing publisher, channels missing from
+    /// the config are added. Returns `false` if the channel was unknown.
+    pub fn register(
+        &mut self,
+        publisher_id: &str,
+        announcement: &ChannelAnnouncement,
+    ) -> bool {
+        let id = &announcement.channel;
+
+        if let Some(channel) =
+            self.channels.iter_mut().find(|channel| channel.is(id))
+        {
+            channel.announced(publisher_id, announcement);
+            return true;
+        }
+
+        let mut channel = Channel::new(&id.name, id.instance);
+        channel.announced(publisher_id, announcement);
+
+        self.channels.push(channel);
+
+        false
+    }
+}
@@ -0,0 +1,493 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nanomsg::{Protocol, Socket};
+use nanomsg::Error as NanomsgError;
+
+use rand::distributions::{IndependentSample, Range};
+use rand::{thread_rng, Rng};
+
+use colored::*;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use codec::CodecKind;
+use filter::{ChannelFilter, ChannelFilters};
+use ticker::{OverrunPolicy, Ticker};
+use types::{ChannelAnnouncement, ChannelId, Heartbeat, Payload, PayloadKind,
+            PubMessage, Quaternion, Vector3};
+use utils::{flush_batch, publish_heartbeat, publish_sample, rate_from_delay,
+            Batcher, Sequencer};
+
+const METRICS_INTERVAL_SECS: u64 = 10;
+
+/// Hardware backing the channels of a publisher
+pub trait Sensor {
+    type Error: Debug;
+
+    /// Sets up the hardware, the runner simulates values if this fails
+    fn init(&mut self) -> Result<(), Self::Error>;
+
+    /// Reads one sample, its messages are published as a single frame
+    fn read(&mut self) -> Result<Vec<PubMessage>, Self::Error>;
+
+    /// Channels announced to the hub and their payload kind
+    fn channels(&self) -> Vec<(ChannelId, PayloadKind)>;
+
+    /// Stand-in sample if no hardware is present
+    fn simulate(&mut self) -> Vec<PubMessage>;
+}
+
+/// Random payload of the given kind, numbers are drawn from `between`
+pub fn random_payload(kind: PayloadKind, between: &Range<f64>) -> Payload {
+    let mut rng = thread_rng();
+
+    match kind {
+        PayloadKind::Bool => Payload::Bool(rng.gen()),
+        PayloadKind::Integer => {
+            Payload::Integer(between.ind_sample(&mut rng) as i64)
+        }
+        PayloadKind::Float => Payload::Float(between.ind_sample(&mut rng)),
+        PayloadKind::Vector3 => Payload::Vector3(Vector3 {
+            x: between.ind_sample(&mut rng),
+            y: between.ind_sample(&mut rng),
+            z: between.ind_sample(&mut rng),
+        }),
+        PayloadKind::Quaternion => Payload::Quaternion(Quaternion {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }),
+        PayloadKind::Bytes => Payload::Bytes(vec![]),
+    }
+}
+
+/// Handling of failed sensor reads and socket writes
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorPolicy {
+    /// Retry up to `attempts` times, doubling the delay starting at
+    /// `backoff`, the socket is rebound before retrying a write. The sample
+    /// is skipped if all attempts fail
+    Retry { attempts: u32, backoff: Duration },
+    /// Skip the sample and flag it in the metrics
+    Skip,
+    /// Skip samples, but give up after this many consecutive failures
+    Escalate(u32),
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> ErrorPolicy {
+        ErrorPolicy::Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Parses `retry:ATTEMPTS,BACKOFF_MS`, `skip` or `escalate:FAILURES`
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<ErrorPolicy, String> {
+        let invalid = || {
+            format!(
+                "invalid error policy '{}', expected \
+                 retry:ATTEMPTS,BACKOFF_MS, skip or escalate:FAILURES",
+                spec
+            )
+        };
+
+        let (kind, args) = match spec.find(':') {
+            Some(index) => (&spec[..index], &spec[index + 1..]),
+            None => (spec, ""),
+        };
+
+        let params = args.split(',')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+
+        match (kind, &params[..]) {
+            ("retry", &[attempts, backoff]) => Ok(ErrorPolicy::Retry {
+                attempts: attempts,
+                backoff: Duration::from_millis(backoff as u64),
+            }),
+            ("skip", &[]) => Ok(ErrorPolicy::Skip),
+            ("escalate", &[failures]) if failures > 0 => {
+                Ok(ErrorPolicy::Escalate(failures))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Configuration files use the same notation as the `--on-error` flag
+impl<'de> Deserialize<'de> for ErrorPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<ErrorPolicy, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let spec = String::deserialize(deserializer)?;
+
+        spec.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct RunnerMetrics {
+    pub samples: u64,
+    pub simulated_samples: u64,
+    // Samples dropped after a failed read or write
+    pub skipped_samples: u64,
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub retries: u64,
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+}
+
+/// Transport and pacing settings of a publisher
+pub struct RunnerConfig {
+    pub address: String,
+    pub publisher_id: String,
+    pub codec: CodecKind,
+    pub key: Option<String>,
+    // Period of the reads
+    pub interval: Duration,
+    pub overrun_policy: OverrunPolicy,
+    // Samples per frame, batching is disabled below 2
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub error_policy: ErrorPolicy,
+    // Applied to the samples before they are published
+    pub filters: Vec<ChannelFilter>,
+    // A waveform is configured, the hardware is left alone
+    pub simulate: bool,
+}
+
+/// Reads a sensor at a fixed interval and publishes its samples
+pub struct Runner<S: Sensor> {
+    sensor: S,
+    socket: Socket,
+    sequencer: Sequencer,
+    batcher: Option<Batcher>,
+    filters: ChannelFilters,
+    ticker: Ticker,
+    address: String,
+    // Unset after a failed rebind
+    bound: bool,
+    error_policy: ErrorPolicy,
+    // Samples skipped in a row
+    consecutive_failures: u32,
+    // Publishes simulated values without initializing the sensor
+    simulate: bool,
+    metrics: RunnerMetrics,
+    last_report: Instant,
+    started: Instant,
+}
+
+impl<S: Sensor> Runner<S> {
+    pub fn new(
+        sensor: S,
+        config: RunnerConfig,
+    ) -> Result<Runner<S>, NanomsgError> {
+        let mut socket = Socket::new(Protocol::Pub)?;
+        socket.bind(&config.address)?;
+
+        let rate = rate_from_delay(config.interval);
+
+        let channels = sensor
+            .channels()
+            .into_iter()
+            .map(|(channel, kind)| ChannelAnnouncement {
+                channel: channel,
+                kind: kind,
+                rate: rate,
+            })
+            .collect();
+
+        let sequencer = Sequencer::new(
+            &config.publisher_id,
+            config.codec,
+            config.key.as_ref().map(|key| key.as_str()),
+            channels,
+        );
+
+        let batcher = if config.batch_size > 1 {
+            Some(Batcher::new(config.batch_size, config.flush_interval))
+        } else {
+            None
+        };
+
+        Ok(Runner {
+            sensor: sensor,
+            socket: socket,
+            sequencer: sequencer,
+            batcher: batcher,
+            filters: ChannelFilters::new(config.filters),
+            ticker: Ticker::new(config.interval, config.overrun_policy),
+            address: config.address,
+            bound: true,
+            error_policy: config.error_policy,
+            consecutive_failures: 0,
+            simulate: config.simulate,
+            metrics: RunnerMetrics::default(),
+            last_report: Instant::now(),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn metrics(&self) -> &RunnerMetrics {
+        &self.metrics
+    }
+
+    /// Publishes samples until the error policy gives up, simulated ones if
+    /// a simulation is configured or the sensor could not be initialized
+    pub fn run(&mut self) -> Result<(), String> {
+        let simulated = if self.simulate {
+            println!("{}", "Publishing simulated values".yellow());
+            true
+        } else {
+            self.init()
+        };
+
+        loop {
+            let sample = if simulated {
+                self.metrics.simulated_samples += 1;
+                Ok(self.sensor.simulate())
+            } else {
+                self.attempt(|runner| {
+                    runner.sensor.read().map_err(|err| {
+                        runner.metrics.read_errors += 1;
+
+                        format!("{:?}", err)
+                    })
+                })
+            };
+
+            // Failures carry the number of samples lost with them
+            let result = match sample {
+                Ok(mut sample) => {
+                    self.filters.apply_all(&mut sample);
+                    self.publish(&sample)
+                        .map_err(|err| (err, self.drop_batch()))
+                }
+                Err(err) => Err((err, 1)),
+            };
+
+            match result {
+                Ok(()) => self.consecutive_failures = 0,
+                Err((err, dropped)) => self.skip(err, dropped)?,
+            }
+
+            self.heartbeat(simulated);
+            self.report();
+
+            self.ticker.wait();
+        }
+    }
+
+    // Initializes the sensor, returns whether simulated values are published
+    // instead
+    fn init(&mut self) -> bool {
+        match self.sensor.init() {
+            Ok(()) => false,
+            Err(err) => {
+                println!(
+                    "{}",
+                    format!("Publishing simulated values ({:?})", err)
+                        .yellow()
+                );
+                true
+            }
+        }
+    }
+
+    // Runs `operation` once more per retry of the error policy
+    fn attempt<T, F>(&mut self, mut operation: F) -> Result<T, String>
+    where
+        F: FnMut(&mut Runner<S>) -> Result<T, String>,
+    {
+        let (attempts, mut backoff) = match self.error_policy {
+            ErrorPolicy::Retry { attempts, backoff } => (attempts, backoff),
+            _ => (0, Duration::from_millis(0)),
+        };
+
+        let mut retry = 0;
+
+        loop {
+            match operation(self) {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    if retry >= attempts {
+                        return Err(err);
+                    }
+
+                    println!(
+                        "{}",
+                        format!("Retrying in {:?} ({})", backoff, err).yellow()
+                    );
+
+                    self.metrics.retries += 1;
+                    retry += 1;
+
+                    sleep(backoff);
+                    backoff = backoff * 2;
+                }
+            }
+        }
+    }
+
+    fn publish(&mut self, sample: &[PubMessage]) -> Result<(), String> {
+        if sample.is_empty() {
+            return Ok(());
+        }
+
+        self.metrics.samples += 1;
+
+        // Queued once, retries only repeat the flush
+        if let Some(ref mut batcher) = self.batcher {
+            self.metrics.skipped_samples += batcher.push(sample) as u64;
+
+            if !batcher.is_due() {
+                return Ok(());
+            }
+        }
+
+        self.attempt(|runner| {
+            // Writes to an unbound socket would silently go nowhere
+            if !runner.bound {
+                runner.reconnect();
+
+                if !runner.bound {
+                    return Err(format!("{} is not bound", runner.address));
+                }
+            }
+
+            let result = match runner.batcher {
+                Some(ref mut batcher) => flush_batch(
+                    &mut runner.socket,
+                    &mut runner.sequencer,
+                    batcher,
+                ),
+                None => publish_sample(
+                    &mut runner.socket,
+                    &mut runner.sequencer,
+                    sample,
+                ),
+            };
+
+            result.map_err(|err| {
+                runner.metrics.write_errors += 1;
+                runner.reconnect();
+
+                err.to_string()
+            })
+        })
+    }
+
+    // Replaces the socket, the address is only released once the previous
+    // one is closed
+    fn reconnect(&mut self) {
+        self.bound = false;
+        self.metrics.reconnects += 1;
+
+        let result = Socket::new(Protocol::Pub).and_then(|socket| {
+            self.socket = socket;
+            self.socket.bind(&self.address)
+        });
+
+        match result {
+            Ok(_) => self.bound = true,
+            Err(err) => println!(
+                "{}",
+                format!("Rebinding {} failed ({})", self.address, err).red()
+            ),
+        }
+    }
+
+    // Drops the batch that could not be published, returns the number of
+    // samples lost
+    fn drop_batch(&mut self) -> u64 {
+        match self.batcher {
+            Some(ref mut batcher) => batcher.clear() as u64,
+            None => 1,
+        }
+    }
+
+    // Counts the dropped samples, fails once the error policy gives up
+    fn skip(&mut self, err: String, dropped: u64) -> Result<(), String> {
+        self.metrics.skipped_samples += dropped;
+        self.consecutive_failures += 1;
+
+        println!(
+            "{}",
+            format!("Skipping {} sample(s) ({})", dropped, err).red()
+        );
+
+        if let ErrorPolicy::Escalate(failures) = self.error_policy {
+            if self.consecutive_failures >= failures {
+                return Err(format!(
+                    "giving up after {} consecutive failures ({})",
+                    self.consecutive_failures, err
+                ));
+            }
+        }
+
+        self.metrics.last_error = Some(err);
+
+        Ok(())
+    }
+
+    // Heartbeats are not retried, the next one is due shortly
+    fn heartbeat(&mut self, simulated: bool) {
+        if !self.bound || !self.sequencer.is_heartbeat_due() {
+            return;
+        }
+
+        let heartbeat = Heartbeat {
+            uptime: self.started.elapsed().as_secs(),
+            samples: self.metrics.samples,
+            skipped_samples: self.metrics.skipped_samples,
+            simulated: simulated,
+        };
+
+        let result =
+            publish_heartbeat(&mut self.socket, &mut self.sequencer, heartbeat);
+
+        if let Err(err) = result {
+            self.metrics.write_errors += 1;
+            self.metrics.last_error = Some(err.to_string());
+        }
+    }
+
+    fn report(&mut self) {
+        let interval = Duration::from_secs(METRICS_INTERVAL_SECS);
+
+        if self.last_report.elapsed() < interval {
+            return;
+        }
+
+        self.last_report = Instant::now();
+
+        let ticker_stats = self.ticker.stats();
+
+        println!(
+            "Published {} sample(s) ({} simulated, {} skipped), \
+             {} read error(s), {} write error(s), {} overrun(s), \
+             jitter {:.0} us mean {} us max",
+            self.metrics.samples,
+            self.metrics.simulated_samples,
+            self.metrics.skipped_samples,
+            self.metrics.read_errors,
+            self.metrics.write_errors,
+            ticker_stats.overruns,
+            ticker_stats.mean_jitter_micros(),
+            ticker_stats.max_jitter_micros
+        );
+    }
+}
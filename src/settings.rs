@@ -0,0 +1,184 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use config::{Config, ConfigError, File};
+use serde::de::DeserializeOwned;
+
+use codec::CodecKind;
+use filter::ChannelFilter;
+use sensor::{ErrorPolicy, RunnerConfig};
+use simulation::{Simulation, Waveform};
+use ticker::OverrunPolicy;
+use utils::config_stem;
+
+/// File all binaries read unless given another one, describes a whole rig
+pub const RIG_CONFIG: &'static str = "9001d";
+
+// `9001D_TARGET_ALTITUDE` sets `target_altitude`, a double underscore
+// separates sections, e.g. `9001D_GP2D12_PUB__DELAY`
+const ENV_PREFIX: &'static str = "9001D_";
+const ENV_SEPARATOR: &'static str = "__";
+
+// Formats `config_stem` accepts
+const EXTENSIONS: [&'static str; 4] = ["toml", "json", "yaml", "hjson"];
+
+/// File `Settings::load` reads for `path`, if it exists
+pub fn config_path(path: Option<&str>) -> Option<PathBuf> {
+    match path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => EXTENSIONS
+            .iter()
+            .map(|ext| PathBuf::from(format!("{}.{}", RIG_CONFIG, ext)))
+            .find(|path| path.is_file()),
+    }
+}
+
+/// `file` in the directory of the config `Settings::load` reads for `path`,
+/// in the working directory if there is none
+pub fn beside_config(path: Option<&str>, file: &str) -> PathBuf {
+    config_path(path)
+        .and_then(|config| config.parent().map(Path::to_path_buf))
+        .unwrap_or_else(PathBuf::new)
+        .join(file)
+}
+
+/// Configuration layered as defaults, file, environment and CLI flags.
+/// Defaults come from the `Default` implementation of the typed sections,
+/// CLI flags are applied to the sections with `layer`.
+pub struct Settings {
+    config: Config,
+}
+
+impl Settings {
+    /// Reads `path` if given, otherwise the rig config, which may only be
+    /// missing if not `required`
+    pub fn load(
+        path: Option<&str>,
+        required: bool,
+    ) -> Result<Settings, ConfigError> {
+        let mut config = Config::default();
+
+        let file = match path {
+            Some(path) => match config_stem(path) {
+                Some(stem) => File::with_name(stem),
+                None => {
+                    return Err(ConfigError::Message(String::from(
+                        "could not load from specified config path",
+                    )));
+                }
+            },
+            None => File::with_name(RIG_CONFIG).required(required),
+        };
+
+        config.merge(file)?;
+
+        for (name, value) in env::vars() {
+            if name.starts_with(ENV_PREFIX) {
+                let key = name[ENV_PREFIX.len()..]
+                    .to_lowercase()
+                    .replace(ENV_SEPARATOR, ".");
+
+                config.set(&key, value)?;
+            }
+        }
+
+        Ok(Settings { config: config })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn into_config(self) -> Config {
+        self.config
+    }
+
+    /// Typed section, missing keys and sections take the defaults
+    pub fn section<T>(&self, name: &str) -> Result<T, ConfigError>
+    where
+        T: DeserializeOwned + Default,
+    {
+        match self.config.get::<T>(name) {
+            Err(ConfigError::NotFound(_)) => Ok(T::default()),
+            result => result,
+        }
+    }
+}
+
+/// Overrides a setting with a CLI flag if it was given
+pub fn layer<T>(setting: &mut T, flag: Option<T>) {
+    if let Some(flag) = flag {
+        *setting = flag;
+    }
+}
+
+/// Settings shared by all publishers, read from the publisher's section
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PublisherSettings {
+    // Defaults to the publisher's own address
+    pub address: Option<String>,
+    // Defaults to the binary name
+    pub publisher_id: Option<String>,
+    pub codec: CodecKind,
+    // Pre-shared key frames are signed with, required on TCP transports
+    pub key: Option<String>,
+    // Simulated waveform without hardware
+    pub simulate: Option<Waveform>,
+    // Standard deviation of the noise added to simulated values
+    pub noise: f64,
+    pub seed: u64,
+    pub on_error: ErrorPolicy,
+    pub overrun: OverrunPolicy,
+    pub filters: Vec<ChannelFilter>,
+}
+
+impl Default for PublisherSettings {
+    fn default() -> PublisherSettings {
+        PublisherSettings {
+            address: None,
+            publisher_id: None,
+            codec: CodecKind::default(),
+            key: None,
+            simulate: None,
+            noise: 0.0,
+            seed: 0,
+            on_error: ErrorPolicy::default(),
+            overrun: OverrunPolicy::default(),
+            filters: vec![],
+        }
+    }
+}
+
+impl PublisherSettings {
+    pub fn simulation(&self, interval: Duration) -> Option<Simulation> {
+        self.simulate.clone().map(|waveform| {
+            Simulation::new(waveform, self.noise, self.seed, interval)
+        })
+    }
+
+    /// `name` is the default publisher id
+    pub fn runner_config(
+        self,
+        name: &str,
+        default_address: &str,
+        interval: Duration,
+    ) -> RunnerConfig {
+        RunnerConfig {
+            address: self.address
+                .unwrap_or_else(|| default_address.to_string()),
+            publisher_id: self.publisher_id
+                .unwrap_or_else(|| name.to_string()),
+            codec: self.codec,
+            key: self.key,
+            interval: interval,
+            overrun_policy: self.overrun,
+            batch_size: 1,
+            flush_interval: Duration::from_millis(0),
+            error_policy: self.on_error,
+            filters: self.filters,
+            simulate: self.simulate.is_some(),
+        }
+    }
+}
@@ -4,11 +4,26 @@ extern crate lazy_static;
 extern crate serde_derive;
 
 extern crate bincode;
-extern crate clap;
 extern crate colored;
 extern crate config;
+extern crate crc;
+extern crate hmac;
 extern crate nanomsg;
 extern crate rand;
+extern crate rmp_serde;
+extern crate serde;
+extern crate serde_cbor;
+extern crate serde_json;
+extern crate sha2;
 
+pub mod calibration;
+pub mod codec;
+pub mod filter;
+pub mod registry;
+pub mod sensor;
+pub mod settings;
+pub mod simulation;
+pub mod ticker;
 pub mod types;
+pub mod units;
 pub mod utils;
@@ -0,0 +1,268 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::distributions::{IndependentSample, Normal};
+use rand::{SeedableRng, XorShiftRng};
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use types::{Payload, PayloadKind};
+
+/// Signal shapes of simulated channels, time is in seconds since the start
+/// of the simulation
+#[derive(Clone, Debug)]
+pub enum Waveform {
+    Constant(f64),
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period: f64,
+    },
+    Ramp { start: f64, slope: f64 },
+    Step { before: f64, after: f64, at: f64 },
+    // Drop from `height` at `at` until impact, `gravity` is in the unit of
+    // `height` per s²
+    FreeFall { height: f64, at: f64, gravity: f64 },
+    // Recorded (time, value) rows, each value is held until the next row
+    Csv(Vec<(f64, f64)>),
+}
+
+impl Waveform {
+    pub fn value(&self, time: f64) -> f64 {
+        match *self {
+            Waveform::Constant(value) => value,
+            Waveform::Sine {
+                offset,
+                amplitude,
+                period,
+            } => offset + amplitude * (2.0 * PI * time / period).sin(),
+            Waveform::Ramp { start, slope } => start + slope * time,
+            Waveform::Step { before, after, at } => {
+                if time < at {
+                    before
+                } else {
+                    after
+                }
+            }
+            Waveform::FreeFall {
+                height,
+                at,
+                gravity,
+            } => {
+                if time < at {
+                    height
+                } else {
+                    let fallen = gravity / 2.0 * (time - at).powi(2);
+
+                    (height - fallen).max(0.0)
+                }
+            }
+            Waveform::Csv(ref rows) => rows.iter()
+                .take_while(|&&(row_time, _)| row_time <= time)
+                .last()
+                .or(rows.first())
+                .map(|&(_, value)| value)
+                .unwrap_or(0.0),
+        }
+    }
+
+    /// Whether a free fall is in progress, `None` for other waveforms
+    pub fn falling(&self, time: f64) -> Option<bool> {
+        match *self {
+            Waveform::FreeFall { at, .. } => {
+                Some(time >= at && self.value(time) > 0.0)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn read_csv(path: &str) -> Result<Waveform, String> {
+    let file = File::open(path)
+        .map_err(|err| format!("could not open {} ({})", path, err))?;
+
+    let mut rows = Vec::new();
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| err.to_string())?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let columns: Vec<Result<f64, _>> =
+            line.split(',').map(|column| column.trim().parse()).collect();
+
+        match &columns[..] {
+            &[Ok(time), Ok(value)] => rows.push((time, value)),
+            // Header row
+            _ if index == 0 => continue,
+            _ => {
+                return Err(format!(
+                    "{}:{}: expected time,value",
+                    path,
+                    index + 1
+                ))
+            }
+        }
+    }
+
+    if rows.is_empty() {
+        return Err(format!("{} contains no rows", path));
+    }
+
+    Ok(Waveform::Csv(rows))
+}
+
+/// Parses `kind:param,param,...`, e.g. `sine:40,10,2` or `csv:drop.csv`
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Waveform, String> {
+        let (kind, args) = match spec.find(':') {
+            Some(index) => (&spec[..index], &spec[index + 1..]),
+            None => (spec, ""),
+        };
+
+        if kind == "csv" {
+            return read_csv(args);
+        }
+
+        let params = args.split(',')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| {
+                arg.trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid parameter '{}'", arg))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+
+        match (kind, &params[..]) {
+            ("constant", &[value]) => Ok(Waveform::Constant(value)),
+            ("sine", &[offset, amplitude, period]) if period > 0.0 => {
+                Ok(Waveform::Sine {
+                    offset: offset,
+                    amplitude: amplitude,
+                    period: period,
+                })
+            }
+            ("ramp", &[start, slope]) => Ok(Waveform::Ramp {
+                start: start,
+                slope: slope,
+            }),
+            ("step", &[before, after, at]) => Ok(Waveform::Step {
+                before: before,
+                after: after,
+                at: at,
+            }),
+            ("free-fall", &[height, at, gravity]) => Ok(Waveform::FreeFall {
+                height: height,
+                at: at,
+                gravity: gravity,
+            }),
+            _ => Err(format!(
+                "invalid simulation '{}', expected constant:VALUE, \
+                 sine:OFFSET,AMPLITUDE,PERIOD, ramp:START,SLOPE, \
+                 step:BEFORE,AFTER,AT, free-fall:HEIGHT,AT,GRAVITY \
+                 (GRAVITY in the channel's unit per s², e.g. 980.665 for cm) \
+                 or csv:PATH",
+                spec
+            )),
+        }
+    }
+}
+
+/// Configuration files use the same notation as the `--simulate` flag
+impl<'de> Deserialize<'de> for Waveform {
+    fn deserialize<D>(deserializer: D) -> Result<Waveform, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let spec = String::deserialize(deserializer)?;
+
+        spec.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Reproducible simulation source, the clock advances by the publishing
+/// interval per sample instead of following the wall clock
+pub struct Simulation {
+    waveform: Waveform,
+    noise: Option<Normal>,
+    rng: XorShiftRng,
+    interval: f64,
+    tick: u64,
+}
+
+impl Simulation {
+    /// `noise` is the standard deviation of the gaussian noise added to each
+    /// value, drawn from a generator seeded with `seed`
+    pub fn new(
+        waveform: Waveform,
+        noise: f64,
+        seed: u64,
+        interval: Duration,
+    ) -> Simulation {
+        // XorShift must not be seeded with all zeros
+        let rng = XorShiftRng::from_seed([
+            seed as u32,
+            (seed >> 32) as u32,
+            0x9e37_79b9,
+            0x7f4a_7c15,
+        ]);
+
+        Simulation {
+            waveform: waveform,
+            noise: if noise > 0.0 {
+                Some(Normal::new(0.0, noise))
+            } else {
+                None
+            },
+            rng: rng,
+            interval: interval.as_secs() as f64
+                + interval.subsec_nanos() as f64 * 1e-9,
+            tick: 0,
+        }
+    }
+
+    /// Simulation time of the next sample
+    pub fn tick(&mut self) -> f64 {
+        let time = self.tick as f64 * self.interval;
+        self.tick += 1;
+
+        time
+    }
+
+    pub fn value(&mut self, time: f64) -> f64 {
+        let value = self.waveform.value(time);
+
+        self.noisy(value)
+    }
+
+    pub fn noisy(&mut self, value: f64) -> f64 {
+        match self.noise {
+            Some(ref noise) => value + noise.ind_sample(&mut self.rng),
+            None => value,
+        }
+    }
+
+    pub fn falling(&self, time: f64) -> Option<bool> {
+        self.waveform.falling(time)
+    }
+
+    /// Next value as a scalar payload, booleans are true from 0.5
+    pub fn payload(&mut self, kind: PayloadKind) -> Payload {
+        let time = self.tick();
+        let value = self.value(time);
+
+        match kind {
+            PayloadKind::Bool => Payload::Bool(value >= 0.5),
+            PayloadKind::Integer => Payload::Integer(value.round() as i64),
+            _ => Payload::Float(value),
+        }
+    }
+}
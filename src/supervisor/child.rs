@@ -0,0 +1,246 @@
+use std::cmp;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use colored::*;
+
+use libc;
+
+// Children still running this long after SIGTERM are killed
+const STOP_TIMEOUT_MS: u64 = 2000;
+
+const STOP_POLL_INTERVAL_MS: u64 = 20;
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Restart delays, doubled after every crash
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    // A child running at least this long is considered healthy again
+    pub reset_after: Duration,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    #[serde(rename = "running")]
+    Running,
+    // Waiting for the backoff to expire before restarting
+    #[serde(rename = "restarting")]
+    Restarting,
+    // Could not be spawned, e.g. the binary is missing
+    #[serde(rename = "failed")]
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChildStatus {
+    pub name: String,
+    pub state: State,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    // Milliseconds since the last start
+    pub uptime: Option<u64>,
+    pub last_exit: Option<String>,
+}
+
+/// Child process restarted with backoff whenever it exits
+pub struct Supervised {
+    name: String,
+    program: PathBuf,
+    args: Vec<String>,
+    backoff: Backoff,
+    process: Option<Child>,
+    started: Option<Instant>,
+    next_start: Instant,
+    delay: Duration,
+    restarts: u32,
+    // The next successful start is counted as a restart
+    restart_pending: bool,
+    last_exit: Option<String>,
+    spawn_failed: bool,
+}
+
+impl Supervised {
+    pub fn new(
+        name: &str,
+        program: PathBuf,
+        args: Vec<String>,
+        backoff: Backoff,
+    ) -> Supervised {
+        Supervised {
+            name: name.to_string(),
+            program: program,
+            args: args,
+            backoff: backoff,
+            process: None,
+            started: None,
+            next_start: Instant::now(),
+            delay: backoff.initial,
+            restarts: 0,
+            restart_pending: false,
+            last_exit: None,
+            spawn_failed: false,
+        }
+    }
+
+    /// Reaps the child if it exited and restarts it once its backoff expired
+    pub fn poll(&mut self) {
+        let exit = match self.process {
+            Some(ref mut process) => match process.try_wait() {
+                Ok(Some(status)) => Some(status.to_string()),
+                Ok(None) => None,
+                Err(err) => Some(err.to_string()),
+            },
+            None => None,
+        };
+
+        if let Some(exit) = exit {
+            self.exited(exit);
+        }
+
+        if self.process.is_none() && Instant::now() >= self.next_start {
+            self.start();
+        }
+    }
+
+    fn exited(&mut self, exit: String) {
+        let healthy = self.started
+            .map(|started| started.elapsed() >= self.backoff.reset_after)
+            .unwrap_or(false);
+
+        if healthy {
+            self.delay = self.backoff.initial;
+        }
+
+        println!(
+            "{}",
+            format!(
+                "{} exited ({}), restarting in {} ms",
+                self.name,
+                exit,
+                millis(self.delay)
+            ).red()
+        );
+
+        self.process = None;
+        self.started = None;
+        self.restart_pending = true;
+        self.last_exit = Some(exit);
+        self.schedule();
+    }
+
+    fn schedule(&mut self) {
+        self.next_start = Instant::now() + self.delay;
+        self.delay = cmp::min(self.delay * 2, self.backoff.max);
+    }
+
+    fn start(&mut self) {
+        match Command::new(&self.program).args(&self.args).spawn() {
+            Ok(process) => {
+                println!(
+                    "{}",
+                    format!(
+                        "Started {} ({}) with pid {}",
+                        self.name,
+                        self.program.display(),
+                        process.id()
+                    ).green()
+                );
+
+                // Spawn failures before the first exit are no restarts
+                if self.restart_pending {
+                    self.restarts += 1;
+                    self.restart_pending = false;
+                }
+
+                self.process = Some(process);
+                self.started = Some(Instant::now());
+                self.spawn_failed = false;
+            }
+            Err(err) => {
+                let exit = format!(
+                    "could not start {} ({})",
+                    self.program.display(),
+                    err
+                );
+
+                println!("{}", format!("{}: {}", self.name, exit).red());
+
+                self.last_exit = Some(exit);
+                self.spawn_failed = true;
+                self.schedule();
+            }
+        }
+    }
+
+    /// Terminates the child and reaps it, it is killed if it does not exit
+    /// within the stop timeout
+    pub fn stop(&mut self) {
+        let mut process = match self.process.take() {
+            Some(process) => process,
+            None => return,
+        };
+
+        self.started = None;
+
+        unsafe {
+            libc::kill(process.id() as libc::pid_t, libc::SIGTERM);
+        }
+
+        let deadline =
+            Instant::now() + Duration::from_millis(STOP_TIMEOUT_MS);
+
+        loop {
+            match process.try_wait() {
+                Ok(Some(status)) => {
+                    println!("Stopped {} ({})", self.name, status);
+                    return;
+                }
+                Ok(None) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(STOP_POLL_INTERVAL_MS))
+                }
+                _ => break,
+            }
+        }
+
+        process.kill().ok();
+        process.wait().ok();
+
+        println!(
+            "{}",
+            format!("Killed {}, it did not stop in time", self.name).red()
+        );
+    }
+
+    pub fn status(&self) -> ChildStatus {
+        let state = if self.process.is_some() {
+            State::Running
+        } else if self.spawn_failed {
+            State::Failed
+        } else {
+            State::Restarting
+        };
+
+        ChildStatus {
+            name: self.name.clone(),
+            state: state,
+            pid: self.process.as_ref().map(|process| process.id()),
+            restarts: self.restarts,
+            uptime: self.started.map(|started| millis(started.elapsed())),
+            last_exit: self.last_exit.clone(),
+        }
+    }
+}
+
+// Children do not outlive a supervisor that panicked
+impl Drop for Supervised {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
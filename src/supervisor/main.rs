@@ -0,0 +1,260 @@
+extern crate iron;
+extern crate mount;
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+#[macro_use]
+extern crate structopt;
+
+extern crate colored;
+extern crate libc;
+
+extern crate shared;
+
+mod child;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use iron::prelude::*;
+use iron::status;
+use mount::Mount;
+
+use colored::*;
+
+use structopt::StructOpt;
+
+use shared::settings::{PublisherSettings, Settings};
+
+use child::{Backoff, ChildStatus, Supervised};
+
+const SECTION: &'static str = "supervisor";
+
+// Interval of reaping and restarting children
+const POLL_INTERVAL_MS: u64 = 100;
+
+static SHUTDOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn on_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+// SIGTERM and SIGINT stop the children before the supervisor exits
+fn handle_shutdown() {
+    unsafe {
+        libc::signal(libc::SIGTERM, on_shutdown as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_shutdown as libc::sighandler_t);
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "supervisor")]
+struct Opt {
+    /// Rig config, passed on to every child
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+}
+
+/// Publisher started by the supervisor, it reads its own section of the
+/// rig config
+#[derive(Deserialize, Clone, Debug)]
+struct ChildSettings {
+    binary: String,
+    // Also selects the section, defaults to the binary name
+    #[serde(default)]
+    publisher_id: Option<String>,
+    // Additional flags
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// `[supervisor]` section of the rig config
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct SupervisorSettings {
+    // Directory of the binaries, defaults to the supervisor's own
+    bin_dir: Option<String>,
+    // Child status is served here as JSON
+    status_address: String,
+    initial_backoff_ms: u64,
+    max_backoff_ms: u64,
+    // The backoff is reset once a child ran this long
+    reset_backoff_after_ms: u64,
+    // Start 9001d connected to all publishers
+    hub: bool,
+    publishers: Vec<ChildSettings>,
+}
+
+impl Default for SupervisorSettings {
+    fn default() -> SupervisorSettings {
+        SupervisorSettings {
+            bin_dir: None,
+            status_address: String::from("0.0.0.0:3001"),
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30000,
+            reset_backoff_after_ms: 10000,
+            hub: true,
+            publishers: vec![],
+        }
+    }
+}
+
+fn bin_dir(settings: &SupervisorSettings) -> PathBuf {
+    match settings.bin_dir {
+        Some(ref bin_dir) => PathBuf::from(bin_dir),
+        None => env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+            .unwrap_or_else(|| PathBuf::from(".")),
+    }
+}
+
+fn status(statuses: &Mutex<Vec<ChildStatus>>) -> IronResult<Response> {
+    let statuses = statuses.lock().unwrap().clone();
+
+    Ok(match serde_json::to_string(&statuses) {
+        Ok(statuses) => Response::with((status::Ok, statuses)),
+        Err(_) => Response::with(status::InternalServerError),
+    })
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let settings =
+        Settings::load(opt.config.as_ref().map(String::as_str), true)
+            .unwrap_or_else(|err| {
+                let message = format!("Could not load config ({})", err);
+
+                eprintln!("{}", message.red());
+                process::exit(1);
+            });
+
+    let supervisor: SupervisorSettings = settings
+        .section(SECTION)
+        .expect("invalid supervisor configuration");
+
+    let bin_dir = bin_dir(&supervisor);
+
+    let backoff = Backoff {
+        initial: Duration::from_millis(supervisor.initial_backoff_ms),
+        max: Duration::from_millis(supervisor.max_backoff_ms),
+        reset_after: Duration::from_millis(supervisor.reset_backoff_after_ms),
+    };
+
+    // Children read the same file
+    let config_args: Vec<String> = match opt.config {
+        Some(ref config) => vec![String::from("--config"), config.clone()],
+        None => vec![],
+    };
+
+    let mut children = Vec::new();
+    let mut addresses = Vec::new();
+
+    for publisher in &supervisor.publishers {
+        let section = publisher
+            .publisher_id
+            .clone()
+            .unwrap_or_else(|| publisher.binary.clone());
+
+        let publisher_settings: PublisherSettings = settings
+            .section(&section)
+            .expect(&format!("invalid configuration of {}", section));
+
+        // Every publisher is given an address so the hub knows all of them
+        let address = publisher_settings
+            .address
+            .unwrap_or_else(|| format!("ipc:///tmp/{}.ipc", section));
+
+        let mut args = config_args.clone();
+
+        if let Some(ref publisher_id) = publisher.publisher_id {
+            args.push(String::from("--publisher-id"));
+            args.push(publisher_id.clone());
+        }
+
+        args.extend(publisher.args.iter().cloned());
+        args.push(address.clone());
+
+        children.push(Supervised::new(
+            &section,
+            bin_dir.join(&publisher.binary),
+            args,
+            backoff,
+        ));
+        addresses.push(address);
+    }
+
+    if supervisor.hub {
+        let mut args = config_args.clone();
+
+        for address in &addresses {
+            args.push(String::from("--publisher"));
+            args.push(address.clone());
+        }
+
+        children.push(Supervised::new(
+            "9001d",
+            bin_dir.join("9001d"),
+            args,
+            backoff,
+        ));
+    }
+
+    if children.is_empty() {
+        eprintln!("{}", "Nothing to supervise".red());
+        process::exit(1);
+    }
+
+    let statuses_arc = Arc::new(Mutex::new(Vec::new()));
+    let statuses_arc_web = statuses_arc.clone();
+
+    let mut mount = Mount::new();
+
+    mount.mount("/status", move |_req: &mut Request| {
+        status(&statuses_arc_web)
+    });
+
+    // Bound before any child is spawned, a taken address would otherwise
+    // leave them running without a supervisor
+    let _server = Iron::new(mount)
+        .http(&supervisor.status_address[..])
+        .unwrap_or_else(|err| {
+            let message = format!(
+                "Could not serve status on {} ({})",
+                supervisor.status_address, err
+            );
+
+            eprintln!("{}", message.red());
+            process::exit(1);
+        });
+
+    handle_shutdown();
+
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        for child in &mut children {
+            child.poll();
+        }
+
+        *statuses_arc.lock().unwrap() =
+            children.iter().map(|child| child.status()).collect();
+
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+
+    println!("Stopping {} children", children.len());
+
+    for child in &mut children {
+        child.stop();
+    }
+
+    // The status server would keep the process alive
+    process::exit(0);
+}
@@ -0,0 +1,130 @@
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Handling of ticks whose deadline passed while the previous one was still
+/// being processed
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum OverrunPolicy {
+    /// Drop the missed ticks and continue with the next future deadline
+    #[serde(rename = "skip")]
+    Skip,
+    /// Run the missed ticks back to back until the schedule is met again
+    #[serde(rename = "catch-up")]
+    CatchUp,
+}
+
+impl Default for OverrunPolicy {
+    fn default() -> OverrunPolicy {
+        OverrunPolicy::Skip
+    }
+}
+
+impl FromStr for OverrunPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<OverrunPolicy, String> {
+        match name {
+            "skip" => Ok(OverrunPolicy::Skip),
+            "catch-up" => Ok(OverrunPolicy::CatchUp),
+            _ => Err(format!("unknown overrun policy '{}'", name)),
+        }
+    }
+}
+
+/// Deviation of the actual tick times from their deadlines
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct TickerStats {
+    pub ticks: u64,
+    pub overruns: u64,
+    pub skipped_ticks: u64,
+    pub max_jitter_micros: u64,
+    pub total_jitter_micros: u64,
+}
+
+impl TickerStats {
+    pub fn mean_jitter_micros(&self) -> f64 {
+        if self.ticks > 0 {
+            self.total_jitter_micros as f64 / self.ticks as f64
+        } else {
+            0.0
+        }
+    }
+
+    fn record(&mut self, jitter: Duration) {
+        let jitter = jitter.as_secs() * 1_000_000
+            + (jitter.subsec_nanos() / 1000) as u64;
+
+        self.ticks += 1;
+        self.total_jitter_micros += jitter;
+
+        if jitter > self.max_jitter_micros {
+            self.max_jitter_micros = jitter;
+        }
+    }
+}
+
+/// Fixed-rate schedule, deadlines are multiples of the period since the
+/// start so the rate does not drift with the time spent between ticks
+pub struct Ticker {
+    period: Duration,
+    deadline: Instant,
+    policy: OverrunPolicy,
+    stats: TickerStats,
+}
+
+impl Ticker {
+    pub fn new(period: Duration, policy: OverrunPolicy) -> Ticker {
+        Ticker {
+            period: period,
+            deadline: Instant::now() + period,
+            policy: policy,
+            stats: TickerStats::default(),
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn stats(&self) -> &TickerStats {
+        &self.stats
+    }
+
+    /// Sleeps until the next deadline, returns by how much it was missed if
+    /// the previous tick overran
+    pub fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+
+        let late = if now < self.deadline {
+            sleep(self.deadline - now);
+            None
+        } else {
+            Some(now - self.deadline)
+        };
+
+        let woken = Instant::now();
+
+        self.stats.record(if woken > self.deadline {
+            woken - self.deadline
+        } else {
+            Duration::from_secs(0)
+        });
+        self.deadline += self.period;
+
+        if late.is_some() {
+            self.stats.overruns += 1;
+
+            let zero = Duration::from_secs(0);
+
+            if self.policy == OverrunPolicy::Skip && self.period > zero {
+                while self.deadline <= woken {
+                    self.deadline += self.period;
+                    self.stats.skipped_ticks += 1;
+                }
+            }
+        }
+
+        late
+    }
+}
@@ -1,28 +1,81 @@
-use bincode::Bounded;
 use bincode::deserialize as bincode_deserialize;
-use bincode::serialize as bincode_serialize;
 
-use std::fmt::{self, Debug, Display};
-use std::mem::size_of;
+use crc::crc32;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
-lazy_static! {
-    // There appears to by a 2 byte overhead when serializing to bincode
-    static ref MSG_SIZE_LIMIT: Bounded = Bounded(
-        size_of::<PubMessage>() as u64 + 8);
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use codec::CodecKind;
+
+/// First byte of every enveloped frame, never a valid bare message start
+pub const FRAME_MAGIC: u8 = 0x9d;
+
+/// Current wire protocol version, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u8 = 9;
+
+/// Version reported for bare messages predating the envelope
+pub const LEGACY_VERSION: u8 = 0;
+
+// Magic byte + version byte + codec tag + flags
+const FRAME_HEADER_SIZE: usize = 4;
+
+// Set if an HMAC-SHA256 follows the body
+const FLAG_AUTHENTICATED: u8 = 0b0000_0001;
+
+const FRAME_MAC_SIZE: usize = 32;
+
+// CRC-32 (IEEE) over header and body, little endian
+const FRAME_CHECKSUM_SIZE: usize = 4;
+
+/// Envelope size varies with the publisher id, the payloads it carries and
+/// the number of batched samples
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Signed frames stamped further than this from the receiver's clock are
+/// rejected, in microseconds
+pub const REPLAY_WINDOW_MICROS: u64 = 5_000_000;
+
+// Names of the channels the controller and the bundled publishers rely on
+pub const PRESSURE_SENSOR_TEMPERATURE: &'static str =
+    "PressureSensorTemperature";
+pub const PRESSURE_SENSOR_PRESSURE: &'static str = "PressureSensorPressure";
+pub const LONG_DISTANCE_SENSOR: &'static str = "LongDistanceSensor";
+pub const ACCELEROMETER_Z: &'static str = "AccelerometerZ";
+pub const GYROSCOPE_X: &'static str = "GyroscopeX";
+pub const GYROSCOPE_Y: &'static str = "GyroscopeY";
+pub const IS_FALLING: &'static str = "IsFalling";
+pub const POWER_BUTTON: &'static str = "PowerButton";
+pub const ACCELERATION: &'static str = "Acceleration";
+pub const MAGNETOMETER: &'static str = "Magnetometer";
+pub const HEADING: &'static str = "Heading";
+
+/// Identifies a channel, the instance distinguishes identical sensors
+#[derive(Serialize, Deserialize, PartialEq, Clone, Eq, Hash, Debug)]
+pub struct ChannelId {
+    pub name: String,
+    pub instance: u8,
 }
 
-macro_rules! define_pub_types {
-    ($Name:ident { $($Variant:ident),* $(,)* }) =>
-    {
-        #[derive(Serialize, Deserialize, PartialEq, Clone, Eq, Hash, Debug)]
-        pub enum $Name {
-            $($Variant),*,
+impl ChannelId {
+    pub fn new(name: &str, instance: u8) -> ChannelId {
+        ChannelId {
+            name: name.to_string(),
+            instance: instance,
         }
-        pub const PUB_TYPES: &'static [$Name] = &[$($Name::$Variant),*];
     }
 }
 
-define_pub_types!(PubType {
+impl Display for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]", self.name, self.instance)
+    }
+}
+
+// Channel enum of bare legacy messages, variant order must not change
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum LegacyPubType {
     PressureSensorTemperature,
     PressureSensorPressure,
     LongDistanceSensor,
@@ -31,40 +84,732 @@ define_pub_types!(PubType {
     GyroscopeY,
     IsFalling,
     PowerButton,
-});
+}
 
-impl Display for PubType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Debug::fmt(self, f)
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct Vector3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Payload {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Vector3(Vector3),
+    Quaternion(Quaternion),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum PayloadKind {
+    Bool,
+    Integer,
+    Float,
+    Vector3,
+    Quaternion,
+    Bytes,
+}
+
+impl Payload {
+    pub fn kind(&self) -> PayloadKind {
+        match *self {
+            Payload::Bool(_) => PayloadKind::Bool,
+            Payload::Integer(_) => PayloadKind::Integer,
+            Payload::Float(_) => PayloadKind::Float,
+            Payload::Vector3(_) => PayloadKind::Vector3,
+            Payload::Quaternion(_) => PayloadKind::Quaternion,
+            Payload::Bytes(_) => PayloadKind::Bytes,
+        }
+    }
+
+    /// Whether all floats of the payload are neither NaN nor infinite
+    pub fn is_finite(&self) -> bool {
+        match *self {
+            Payload::Float(value) => value.is_finite(),
+            Payload::Vector3(ref vector) => {
+                vector.x.is_finite()
+                    && vector.y.is_finite()
+                    && vector.z.is_finite()
+            }
+            Payload::Quaternion(ref quaternion) => {
+                quaternion.w.is_finite()
+                    && quaternion.x.is_finite()
+                    && quaternion.y.is_finite()
+                    && quaternion.z.is_finite()
+            }
+            _ => true,
+        }
+    }
+
+    /// Scalar view of the payload, `None` for compound payloads
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Payload::Bool(value) => Some(if value { 1.0 } else { 0.0 }),
+            Payload::Integer(value) => Some(value as f64),
+            Payload::Float(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Payload::Bool(value) => Some(value as i64),
+            Payload::Integer(value) => Some(value),
+            Payload::Float(value) => Some(value as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Payload::Bool(value) => Some(value),
+            Payload::Integer(value) => Some(value != 0),
+            Payload::Float(value) => Some(value != 0.0),
+            _ => None,
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct PubMessage {
-    pub pub_type: PubType,
-    pub integral: i16,
-    pub decimal: f32,
+    pub channel: ChannelId,
+    pub payload: Payload,
 }
 
-pub fn str_to_pub_type(pub_type: &str) -> Option<PubType> {
-    for pub_type_ in PUB_TYPES {
-        if pub_type_.to_string() == pub_type {
-            return Some(pub_type_.clone());
+impl PubMessage {
+    /// Announcement for the channel of this message template
+    pub fn announcement(&self, rate: f64) -> ChannelAnnouncement {
+        ChannelAnnouncement {
+            channel: self.channel.clone(),
+            kind: self.payload.kind(),
+            rate: rate,
         }
     }
-    None
 }
 
-pub fn serialize(msg: &PubMessage) -> Option<Vec<u8>> {
-    match bincode_serialize(&msg, *MSG_SIZE_LIMIT) {
-        Ok(serialized_message) => Some(serialized_message),
-        Err(_) => None,
+/// Describes a channel a publisher is about to publish on
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ChannelAnnouncement {
+    pub channel: ChannelId,
+    pub kind: PayloadKind,
+    // Nominal publishing rate in Hz
+    pub rate: f64,
+}
+
+/// Message layout predating typed payloads, only used to parse bare frames
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct LegacyPubMessage {
+    pub_type: LegacyPubType,
+    integral: i16,
+    decimal: f32,
+}
+
+impl From<LegacyPubMessage> for PubMessage {
+    fn from(legacy: LegacyPubMessage) -> PubMessage {
+        // Integral readings were mirrored into the decimal part
+        let payload = if legacy.decimal == legacy.integral as f32 {
+            Payload::Integer(legacy.integral as i64)
+        } else {
+            Payload::Float(legacy.decimal as f64)
+        };
+
+        PubMessage {
+            // Variant names match the channel names
+            channel: ChannelId::new(&format!("{:?}", legacy.pub_type), 0),
+            payload: payload,
+        }
     }
 }
 
-pub fn deserialize(msg: &[u8]) -> Option<PubMessage> {
-    match bincode_deserialize(&msg.to_vec()) {
-        Ok(pub_msg) => Some(pub_msg),
+/// Sample queued for a batch, timestamped at acquisition
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Sample {
+    // Microseconds since the UNIX epoch
+    pub timestamp: u64,
+    pub messages: Vec<PubMessage>,
+}
+
+/// Periodic sign of life, also sent while no samples can be read
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Heartbeat {
+    // Seconds since the publisher started
+    pub uptime: u64,
+    pub samples: u64,
+    pub skipped_samples: u64,
+    // Set if the hardware is unavailable and values are simulated
+    pub simulated: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Body {
+    // Published atomically, all messages belong to the same sample
+    Sample(Vec<PubMessage>),
+    // Samples in acquisition order
+    Batch(Vec<Sample>),
+    // Channels a publisher registers with the hub
+    Announce(Vec<ChannelAnnouncement>),
+    Heartbeat(Heartbeat),
+}
+
+/// Wraps a frame body with everything the hub needs to judge its freshness
+/// and origin.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Envelope {
+    // Carried in the frame header, not in the encoded body
+    #[serde(skip)]
+    pub version: u8,
+    // Set by `deserialize` if the frame carried a valid HMAC
+    #[serde(skip)]
+    pub authenticated: bool,
+    pub publisher_id: String,
+    // Incremented per published frame, gaps indicate lost frames
+    pub sequence: u64,
+    // Microseconds since the UNIX epoch at acquisition
+    pub timestamp: u64,
+    pub body: Body,
+}
+
+impl Envelope {
+    pub fn new(
+        publisher_id: &str,
+        sequence: u64,
+        timestamp: u64,
+        body: Body,
+    ) -> Envelope {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            authenticated: false,
+            publisher_id: publisher_id.to_string(),
+            sequence: sequence,
+            timestamp: timestamp,
+            body: body,
+        }
+    }
+
+    /// Wraps a bare message received from a publisher that predates the
+    /// envelope.
+    pub fn from_legacy(message: PubMessage, timestamp: u64) -> Envelope {
+        Envelope {
+            version: LEGACY_VERSION,
+            authenticated: false,
+            publisher_id: String::new(),
+            sequence: 0,
+            timestamp: timestamp,
+            body: Body::Sample(vec![message]),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    /// Whether all payloads carried are finite, see `Payload::is_finite`
+    pub fn is_finite(&self) -> bool {
+        let finite = |messages: &[PubMessage]| {
+            messages.iter().all(|msg| msg.payload.is_finite())
+        };
+
+        match self.body {
+            Body::Sample(ref messages) => finite(messages),
+            Body::Batch(ref samples) => {
+                samples.iter().all(|sample| finite(&sample.messages))
+            }
+            Body::Announce(_) | Body::Heartbeat(_) => true,
+        }
+    }
+}
+
+/// Reasons a received frame is dropped
+#[derive(Serialize, PartialEq, Clone, Copy, Debug)]
+pub enum FrameError {
+    // Shorter than header and checksum
+    Truncated,
+    // Missing the magic byte, possibly a bare legacy message
+    NotEnveloped,
+    UnsupportedVersion(u8),
+    UnknownCodec(u8),
+    ChecksumMismatch,
+    // Checksum matched but the body could not be decoded
+    Malformed,
+    // HMAC did not match the pre-shared key
+    BadSignature,
+    // Missing HMAC on a transport that requires one
+    Unauthenticated,
+    // Signed frame not newer than the last one accepted from its publisher
+    Replayed,
+    // Signed frame stamped outside the replay window
+    OutsideWindow,
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::Truncated => write!(f, "truncated frame"),
+            FrameError::NotEnveloped => write!(f, "missing frame header"),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            FrameError::UnknownCodec(tag) => write!(f, "unknown codec {}", tag),
+            FrameError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            FrameError::Malformed => write!(f, "malformed body"),
+            FrameError::BadSignature => write!(f, "bad signature"),
+            FrameError::Unauthenticated => write!(f, "unauthenticated frame"),
+            FrameError::Replayed => write!(f, "replayed frame"),
+            FrameError::OutsideWindow => {
+                write!(f, "frame timestamp outside the replay window")
+            }
+        }
+    }
+}
+
+fn hmac(key: &[u8], content: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new(key).unwrap();
+    mac.input(content);
+
+    mac
+}
+
+/// Frames are signed with the pre-shared `key` if one is given
+pub fn serialize(
+    envelope: &Envelope,
+    codec: CodecKind,
+    key: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    let body = codec.codec().encode(envelope)?;
+
+    let mut frame = Vec::with_capacity(
+        FRAME_HEADER_SIZE + body.len() + FRAME_MAC_SIZE + FRAME_CHECKSUM_SIZE,
+    );
+
+    // Re-encoded legacy envelopes are sent in the current format
+    frame.push(FRAME_MAGIC);
+    frame.push(PROTOCOL_VERSION);
+    frame.push(codec.tag());
+    frame.push(if key.is_some() { FLAG_AUTHENTICATED } else { 0 });
+    frame.extend_from_slice(&body);
+
+    if let Some(key) = key {
+        let code = hmac(key, &frame).result().code();
+        frame.extend_from_slice(&code);
+    }
+
+    let checksum = crc32::checksum_ieee(&frame);
+
+    for shift in 0..FRAME_CHECKSUM_SIZE {
+        frame.push((checksum >> (shift * 8)) as u8);
+    }
+
+    Some(frame)
+}
+
+/// Codec of an enveloped frame of the current protocol version, the
+/// checksum is not verified
+pub fn frame_codec(frame: &[u8]) -> Result<CodecKind, FrameError> {
+    if frame.is_empty() || frame[0] != FRAME_MAGIC {
+        return Err(FrameError::NotEnveloped);
+    }
+
+    if frame.len() < FRAME_HEADER_SIZE + FRAME_CHECKSUM_SIZE {
+        return Err(FrameError::Truncated);
+    }
+
+    if frame[1] != PROTOCOL_VERSION {
+        return Err(FrameError::UnsupportedVersion(frame[1]));
+    }
+
+    CodecKind::from_tag(frame[2]).ok_or(FrameError::UnknownCodec(frame[2]))
+}
+
+/// Signed frames are verified if the pre-shared `key` is known, the result
+/// is reported through `Envelope::authenticated`
+pub fn deserialize(
+    frame: &[u8],
+    key: Option<&[u8]>,
+) -> Result<Envelope, FrameError> {
+    let codec = frame_codec(frame)?;
+
+    let (content, trailer) = frame.split_at(frame.len() - FRAME_CHECKSUM_SIZE);
+
+    let checksum = trailer
+        .iter()
+        .enumerate()
+        .fold(0_u32, |checksum, (index, byte)| {
+            checksum | ((*byte as u32) << (index * 8))
+        });
+
+    if checksum != crc32::checksum_ieee(content) {
+        return Err(FrameError::ChecksumMismatch);
+    }
+
+    let signed = content[3] & FLAG_AUTHENTICATED != 0;
+
+    let (content, code) = if signed {
+        if content.len() < FRAME_HEADER_SIZE + FRAME_MAC_SIZE {
+            return Err(FrameError::Truncated);
+        }
+
+        let (content, code) = content.split_at(content.len() - FRAME_MAC_SIZE);
+        (content, Some(code))
+    } else {
+        (content, None)
+    };
+
+    let authenticated = match (key, code) {
+        (Some(key), Some(code)) => {
+            if hmac(key, content).verify(code).is_err() {
+                return Err(FrameError::BadSignature);
+            }
+            true
+        }
+        _ => false,
+    };
+
+    let mut envelope = codec
+        .codec()
+        .decode(&content[FRAME_HEADER_SIZE..])
+        .ok_or(FrameError::Malformed)?;
+    envelope.version = frame[1];
+    envelope.authenticated = authenticated;
+
+    Ok(envelope)
+}
+
+/// Last sequence number and timestamp accepted per publisher id. A valid
+/// signature alone does not stop a captured frame from being sent again.
+#[derive(Default, Debug)]
+pub struct ReplayGuard {
+    accepted: HashMap<String, (u64, u64)>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> ReplayGuard {
+        ReplayGuard::default()
+    }
+
+    /// Accepts a signed `envelope` received at `now` in microseconds since
+    /// the UNIX epoch. A restarted publisher counts from zero again, its
+    /// sequence number may only fall back if the timestamp moved on.
+    pub fn check(
+        &mut self,
+        envelope: &Envelope,
+        now: u64,
+    ) -> Result<(), FrameError> {
+        let skew = if envelope.timestamp > now {
+            envelope.timestamp - now
+        } else {
+            now - envelope.timestamp
+        };
+
+        if skew > REPLAY_WINDOW_MICROS {
+            return Err(FrameError::OutsideWindow);
+        }
+
+        if let Some(&(sequence, timestamp)) =
+            self.accepted.get(&envelope.publisher_id)
+        {
+            // Frames built within the same microsecond share a timestamp
+            let newer = envelope.sequence > sequence
+                && envelope.timestamp >= timestamp;
+            let restarted = envelope.sequence <= sequence
+                && envelope.timestamp > timestamp;
+
+            if !(newer || restarted) {
+                return Err(FrameError::Replayed);
+            }
+        }
+
+        self.accepted.insert(
+            envelope.publisher_id.clone(),
+            (envelope.sequence, envelope.timestamp),
+        );
+
+        Ok(())
+    }
+}
+
+/// Parses a bare `LegacyPubMessage` as sent before the envelope was
+/// introduced.
+pub fn deserialize_legacy(msg: &[u8]) -> Option<PubMessage> {
+    match bincode_deserialize::<LegacyPubMessage>(msg) {
+        Ok(legacy_msg) => Some(PubMessage::from(legacy_msg)),
         Err(_) => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f64::NAN;
+
+    use super::*;
+
+    const KEY: &'static [u8] = b"change-me";
+
+    // Microseconds since the UNIX epoch, also the receiver's clock in the
+    // replay tests
+    const NOW: u64 = 1_500_000_000_000_000;
+
+    const CODECS: [CodecKind; 4] = [
+        CodecKind::Bincode,
+        CodecKind::Cbor,
+        CodecKind::MessagePack,
+        CodecKind::Json,
+    ];
+
+    fn msg(name: &str, payload: Payload) -> PubMessage {
+        PubMessage {
+            channel: ChannelId::new(name, 0),
+            payload: payload,
+        }
+    }
+
+    fn envelope(
+        publisher_id: &str,
+        sequence: u64,
+        timestamp: u64,
+    ) -> Envelope {
+        Envelope::new(
+            publisher_id,
+            sequence,
+            timestamp,
+            Body::Sample(vec![
+                msg(LONG_DISTANCE_SENSOR, Payload::Float(42.5)),
+                msg(IS_FALLING, Payload::Bool(true)),
+                msg("Analog", Payload::Integer(1023)),
+                msg(
+                    "Acceleration",
+                    Payload::Vector3(Vector3 {
+                        x: 0.5,
+                        y: -1.25,
+                        z: 9.75,
+                    }),
+                ),
+            ]),
+        )
+    }
+
+    // Appends the checksum to a tampered frame stripped of it
+    fn with_checksum(mut content: Vec<u8>) -> Vec<u8> {
+        let checksum = crc32::checksum_ieee(&content);
+
+        for shift in 0..FRAME_CHECKSUM_SIZE {
+            content.push((checksum >> (shift * 8)) as u8);
+        }
+
+        content
+    }
+
+    #[test]
+    fn frames_round_trip_with_every_codec() {
+        for &codec in &CODECS {
+            let sent = envelope("gp2d12_pub", 7, NOW);
+            let frame = serialize(&sent, codec, None).unwrap();
+
+            assert_eq!(frame_codec(&frame), Ok(codec));
+            assert_eq!(deserialize(&frame, None), Ok(sent));
+        }
+    }
+
+    #[test]
+    fn json_refuses_non_finite_floats() {
+        let sent = Envelope::new(
+            "gp2d12_pub",
+            7,
+            NOW,
+            Body::Sample(vec![msg(ACCELEROMETER_Z, Payload::Float(NAN))]),
+        );
+
+        assert_eq!(serialize(&sent, CodecKind::Json, None), None);
+        assert!(serialize(&sent, CodecKind::Bincode, None).is_some());
+    }
+
+    #[test]
+    fn corrupted_frames_fail_the_checksum() {
+        let sent = envelope("gp2d12_pub", 7, NOW);
+        let frame = serialize(&sent, CodecKind::Bincode, None).unwrap();
+
+        for index in 0..frame.len() {
+            let mut corrupted = frame.clone();
+            corrupted[index] ^= 0x10;
+
+            match deserialize(&corrupted, None) {
+                Err(FrameError::ChecksumMismatch) => {}
+                // The header is checked before the checksum
+                Err(FrameError::NotEnveloped)
+                | Err(FrameError::UnsupportedVersion(_))
+                | Err(FrameError::UnknownCodec(_)) if index < 3 => {}
+                result => panic!("byte {} flipped: {:?}", index, result),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let sent = envelope("gp2d12_pub", 7, NOW);
+        let frame = serialize(&sent, CodecKind::Bincode, None).unwrap();
+
+        assert_eq!(
+            deserialize(&frame[..FRAME_HEADER_SIZE], None),
+            Err(FrameError::Truncated)
+        );
+        assert_eq!(
+            deserialize(&frame[..frame.len() - 1], None),
+            Err(FrameError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn signed_frames_are_authenticated_with_the_key() {
+        for &codec in &CODECS {
+            let sent = envelope("gp2d12_pub", 7, NOW);
+            let frame = serialize(&sent, codec, Some(KEY)).unwrap();
+
+            let received = deserialize(&frame, Some(KEY)).unwrap();
+            assert!(received.authenticated);
+            assert_eq!(received.body, sent.body);
+
+            // Without the key the signature can't be verified
+            assert!(!deserialize(&frame, None).unwrap().authenticated);
+        }
+    }
+
+    #[test]
+    fn tampered_signed_frames_are_rejected() {
+        let sent = envelope("gp2d12_pub", 7, NOW);
+        let frame = serialize(&sent, CodecKind::Json, Some(KEY)).unwrap();
+        let content = &frame[..frame.len() - FRAME_CHECKSUM_SIZE];
+
+        // A changed body with a valid checksum
+        let tampered: Vec<u8> = content
+            .iter()
+            .map(|&byte| if byte == b'7' { b'8' } else { byte })
+            .collect();
+        assert_ne!(&tampered[..], content);
+
+        assert_eq!(
+            deserialize(&with_checksum(tampered), Some(KEY)),
+            Err(FrameError::BadSignature)
+        );
+
+        // Signed with a different key
+        let forged = serialize(&sent, CodecKind::Json, Some(b"guess")).unwrap();
+
+        assert_eq!(
+            deserialize(&forged, Some(KEY)),
+            Err(FrameError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn stripped_signatures_downgrade_to_unauthenticated() {
+        let sent = envelope("gp2d12_pub", 7, NOW);
+        let frame = serialize(&sent, CodecKind::Bincode, Some(KEY)).unwrap();
+
+        // Flag cleared and HMAC cut off, the frame is otherwise intact
+        let mut stripped =
+            frame[..frame.len() - FRAME_CHECKSUM_SIZE - FRAME_MAC_SIZE]
+                .to_vec();
+        stripped[3] &= !FLAG_AUTHENTICATED;
+
+        let received = deserialize(&with_checksum(stripped), Some(KEY));
+
+        let received = received.unwrap();
+
+        assert_eq!(received.body, sent.body);
+        assert!(!received.authenticated);
+    }
+
+    #[test]
+    fn replay_guard_accepts_newer_frames() {
+        let mut guard = ReplayGuard::new();
+
+        for sequence in 0..5 {
+            let timestamp = NOW + sequence * 1000;
+
+            assert_eq!(
+                guard.check(&envelope("gp2d12_pub", sequence, timestamp), NOW),
+                Ok(())
+            );
+        }
+
+        // Frames built within the same microsecond share a timestamp
+        assert_eq!(
+            guard.check(&envelope("gp2d12_pub", 5, NOW + 4000), NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn replay_guard_rejects_replayed_frames() {
+        let mut guard = ReplayGuard::new();
+        let captured = envelope("gp2d12_pub", 3, NOW);
+
+        assert_eq!(guard.check(&captured, NOW), Ok(()));
+        assert_eq!(guard.check(&captured, NOW), Err(FrameError::Replayed));
+        assert_eq!(
+            guard.check(&envelope("gp2d12_pub", 2, NOW - 1000), NOW),
+            Err(FrameError::Replayed)
+        );
+
+        // Publishers are tracked separately
+        assert_eq!(guard.check(&envelope("gpio_pub", 3, NOW), NOW), Ok(()));
+    }
+
+    #[test]
+    fn replay_guard_accepts_restarted_publishers() {
+        let mut guard = ReplayGuard::new();
+
+        assert_eq!(
+            guard.check(&envelope("gp2d12_pub", 500, NOW), NOW),
+            Ok(())
+        );
+
+        // Counting from zero again after the last frame
+        assert_eq!(
+            guard.check(&envelope("gp2d12_pub", 0, NOW + 1), NOW),
+            Ok(())
+        );
+        assert_eq!(
+            guard.check(&envelope("gp2d12_pub", 1, NOW + 2), NOW),
+            Ok(())
+        );
+        assert_eq!(
+            guard.check(&envelope("gp2d12_pub", 500, NOW), NOW),
+            Err(FrameError::Replayed)
+        );
+    }
+
+    #[test]
+    fn replay_guard_rejects_frames_outside_the_window() {
+        let mut guard = ReplayGuard::new();
+
+        for &timestamp in &[
+            NOW - REPLAY_WINDOW_MICROS - 1,
+            NOW + REPLAY_WINDOW_MICROS + 1,
+        ] {
+            assert_eq!(
+                guard.check(&envelope("gp2d12_pub", 1, timestamp), NOW),
+                Err(FrameError::OutsideWindow)
+            );
+        }
+
+        assert_eq!(
+            guard.check(
+                &envelope("gp2d12_pub", 1, NOW - REPLAY_WINDOW_MICROS),
+                NOW
+            ),
+            Ok(())
+        );
+    }
+}
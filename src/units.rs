@@ -0,0 +1,2 @@
+/// Standard gravity in m/s²
+pub const GRAVITY: f64 = 9.80665;
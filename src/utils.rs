@@ -1,56 +1,334 @@
+use std::fmt;
+use std::io;
 use std::io::Write;
 use std::path::Path;
-use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::{App, Arg};
-use config::{Config, ConfigError, File};
 use nanomsg::Socket;
 
-use rand::distributions::{IndependentSample, Range};
-use rand::thread_rng;
+use codec::CodecKind;
+use types::{serialize, Body, ChannelAnnouncement, Envelope, Heartbeat,
+            Payload, PubMessage, Quaternion, Sample, Vector3};
 
-use colored::*;
+// Publishers may start before the hub connects, so channels are re-announced
+const ANNOUNCE_INTERVAL_SECS: u64 = 1;
 
-use types::{serialize, PubMessage};
+const HEARTBEAT_INTERVAL_MS: u64 = 250;
 
-pub fn publish_random_values(
-    mut socket: Socket,
-    mut msg: PubMessage,
-    sleep_duration: Duration,
-    between: Range<f32>,
-) {
-    println!("{}", "Publishing random values".yellow());
+/// Nominal rate in Hz of a publisher sleeping `delay` between samples
+pub fn rate_from_delay(delay: Duration) -> f64 {
+    let secs = delay.as_secs() as f64 + delay.subsec_nanos() as f64 * 1e-9;
+
+    if secs > 0.0 {
+        1.0 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Microseconds since the UNIX epoch
+pub fn timestamp_micros() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => {
+            elapsed.as_secs() * 1_000_000
+                + (elapsed.subsec_nanos() / 1000) as u64
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Wraps outgoing messages in envelopes carrying the publisher id and a
+/// per-publisher sequence number.
+pub struct Sequencer {
+    publisher_id: String,
+    sequence: u64,
+    codec: CodecKind,
+    // Pre-shared key frames are signed with, required on TCP transports
+    key: Option<Vec<u8>>,
+    // Channels registered with the hub through announcements
+    channels: Vec<ChannelAnnouncement>,
+    last_announce: Option<Instant>,
+    last_heartbeat: Option<Instant>,
+}
+
+impl Sequencer {
+    pub fn new(
+        publisher_id: &str,
+        codec: CodecKind,
+        key: Option<&str>,
+        channels: Vec<ChannelAnnouncement>,
+    ) -> Sequencer {
+        Sequencer {
+            publisher_id: publisher_id.to_string(),
+            sequence: 0,
+            codec: codec,
+            key: key.map(|key| key.as_bytes().to_vec()),
+            channels: channels,
+            last_announce: None,
+            last_heartbeat: None,
+        }
+    }
+
+    pub fn envelope(&mut self, body: Body) -> Envelope {
+        let envelope = Envelope::new(
+            &self.publisher_id,
+            self.sequence,
+            timestamp_micros(),
+            body,
+        );
+
+        self.sequence = self.sequence.wrapping_add(1);
+
+        envelope
+    }
+
+    /// Announcement of the publisher's channels, if one is due
+    pub fn announcement(&mut self) -> Option<Envelope> {
+        let due = match self.last_announce {
+            Some(last_announce) => {
+                last_announce.elapsed()
+                    >= Duration::from_secs(ANNOUNCE_INTERVAL_SECS)
+            }
+            None => true,
+        };
+
+        if !due {
+            return None;
+        }
+
+        self.last_announce = Some(Instant::now());
+
+        let channels = self.channels.clone();
+        Some(self.envelope(Body::Announce(channels)))
+    }
+
+    pub fn is_heartbeat_due(&self) -> bool {
+        match self.last_heartbeat {
+            Some(last_heartbeat) => {
+                last_heartbeat.elapsed()
+                    >= Duration::from_millis(HEARTBEAT_INTERVAL_MS)
+            }
+            None => true,
+        }
+    }
+
+    pub fn heartbeat(&mut self, heartbeat: Heartbeat) -> Envelope {
+        self.last_heartbeat = Some(Instant::now());
+
+        self.envelope(Body::Heartbeat(heartbeat))
+    }
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    // The envelope exceeds the frame size limit of the codec or carries
+    // values it cannot represent
+    Encode,
+    Write(io::Error),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PublishError::Encode => write!(f, "could not encode envelope"),
+            PublishError::Write(ref err) => write!(f, "write failed ({})", err),
+        }
+    }
+}
+
+pub fn publish(
+    socket: &mut Socket,
+    sequencer: &mut Sequencer,
+    msg: &PubMessage,
+) -> Result<(), PublishError> {
+    publish_sample(socket, sequencer, &[msg.clone()])
+}
+
+/// Publishes all messages of one sample as a single frame
+pub fn publish_sample(
+    socket: &mut Socket,
+    sequencer: &mut Sequencer,
+    msgs: &[PubMessage],
+) -> Result<(), PublishError> {
+    if let Some(announcement) = sequencer.announcement() {
+        write_envelope(socket, &announcement, sequencer)?;
+    }
+
+    let envelope = sequencer.envelope(Body::Sample(msgs.to_vec()));
+
+    write_envelope(socket, &envelope, sequencer)
+}
+
+pub fn publish_heartbeat(
+    socket: &mut Socket,
+    sequencer: &mut Sequencer,
+    heartbeat: Heartbeat,
+) -> Result<(), PublishError> {
+    let envelope = sequencer.heartbeat(heartbeat);
+
+    write_envelope(socket, &envelope, sequencer)
+}
+
+/// Collects samples of high-rate publishers into a single frame
+pub struct Batcher {
+    samples: Vec<Sample>,
+    batch_size: usize,
+    flush_interval: Duration,
+    // Acquisition of the oldest queued sample
+    oldest: Option<Instant>,
+}
+
+impl Batcher {
+    pub fn new(batch_size: usize, flush_interval: Duration) -> Batcher {
+        Batcher {
+            samples: Vec::with_capacity(batch_size),
+            batch_size: batch_size,
+            flush_interval: flush_interval,
+            oldest: None,
+        }
+    }
+
+    /// Queues a sample, a full batch drops its oldest samples to make room.
+    /// Returns the number of samples dropped.
+    pub fn push(&mut self, msgs: &[PubMessage]) -> usize {
+        if self.oldest.is_none() {
+            self.oldest = Some(Instant::now());
+        }
 
-    let mut rng = thread_rng();
-    loop {
-        msg = fill_message_decimal(between.ind_sample(&mut rng), msg);
+        // Keeps a batch the hub cannot take from growing on every failure
+        let capacity = self.batch_size.max(1);
+        let dropped = (self.samples.len() + 1).saturating_sub(capacity);
+        self.samples.drain(..dropped);
+
+        self.samples.push(Sample {
+            timestamp: timestamp_micros(),
+            messages: msgs.to_vec(),
+        });
+
+        dropped
+    }
 
-        if let Err(err) = socket.write_all(&serialize(&msg).unwrap()[..]) {
-            panic!(err);
+    /// Full batches and batches older than the flush interval are due
+    pub fn is_due(&self) -> bool {
+        match self.oldest {
+            Some(oldest) => {
+                self.samples.len() >= self.batch_size
+                    || oldest.elapsed() >= self.flush_interval
+            }
+            None => false,
         }
-        sleep(sleep_duration);
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Drops all queued samples, returns how many were dropped
+    pub fn clear(&mut self) -> usize {
+        let dropped = self.samples.len();
+
+        self.oldest = None;
+        self.samples.clear();
+
+        dropped
     }
 }
 
-pub fn publish(socket: &mut Socket, msg: &PubMessage) {
-    if let Err(err) = socket.write_all(&serialize(msg).unwrap()[..]) {
-        panic!(err);
+/// Queues one sample and publishes the batch once it is due, a full batch
+/// that could not be published loses its oldest samples
+pub fn publish_batched(
+    socket: &mut Socket,
+    sequencer: &mut Sequencer,
+    batcher: &mut Batcher,
+    msgs: &[PubMessage],
+) -> Result<(), PublishError> {
+    batcher.push(msgs);
+
+    if batcher.is_due() {
+        flush_batch(socket, sequencer, batcher)
+    } else {
+        Ok(())
     }
 }
 
-pub fn fill_message_decimal(decimal: f32, mut msg: PubMessage) -> PubMessage {
-    msg.decimal = decimal;
+/// Publishes all queued samples regardless of the flush interval, they stay
+/// queued if publishing fails
+pub fn flush_batch(
+    socket: &mut Socket,
+    sequencer: &mut Sequencer,
+    batcher: &mut Batcher,
+) -> Result<(), PublishError> {
+    if let Some(announcement) = sequencer.announcement() {
+        write_envelope(socket, &announcement, sequencer)?;
+    }
 
-    msg.integral = decimal as i16;
+    let envelope =
+        sequencer.envelope(Body::Batch(batcher.samples().to_vec()));
+
+    write_envelope(socket, &envelope, sequencer)?;
+    batcher.clear();
+
+    Ok(())
+}
+
+fn write_envelope(
+    socket: &mut Socket,
+    envelope: &Envelope,
+    sequencer: &Sequencer,
+) -> Result<(), PublishError> {
+    let key = sequencer.key.as_ref().map(|key| &key[..]);
+    let frame = serialize(envelope, sequencer.codec, key)
+        .ok_or(PublishError::Encode)?;
+
+    socket.write_all(&frame[..]).map_err(PublishError::Write)
+}
+
+pub fn fill_message_bool(value: bool, mut msg: PubMessage) -> PubMessage {
+    msg.payload = Payload::Bool(value);
 
     msg
 }
 
-pub fn fill_message_integral(integral: i16, mut msg: PubMessage) -> PubMessage {
-    msg.integral = integral;
+pub fn fill_message_integer(value: i64, mut msg: PubMessage) -> PubMessage {
+    msg.payload = Payload::Integer(value);
+
+    msg
+}
 
-    msg.decimal = msg.integral as f32;
+pub fn fill_message_float(value: f64, mut msg: PubMessage) -> PubMessage {
+    msg.payload = Payload::Float(value);
+
+    msg
+}
+
+pub fn fill_message_vector3(
+    value: Vector3,
+    mut msg: PubMessage,
+) -> PubMessage {
+    msg.payload = Payload::Vector3(value);
+
+    msg
+}
+
+pub fn fill_message_quaternion(
+    value: Quaternion,
+    mut msg: PubMessage,
+) -> PubMessage {
+    msg.payload = Payload::Quaternion(value);
+
+    msg
+}
+
+pub fn fill_message_bytes(value: Vec<u8>, mut msg: PubMessage) -> PubMessage {
+    msg.payload = Payload::Bytes(value);
 
     msg
 }
@@ -70,40 +348,3 @@ pub fn path_without_extension(path: &Path) -> Option<&str> {
 
     path.to_str().map(|s| &s[..s.len() - ext.len()])
 }
-
-pub fn get_config(app_name: &str) -> Result<Config, ConfigError> {
-    let mut config = Config::default();
-
-    let matches = App::new(app_name)
-        .author("Philip Trauner <philip.trauner@arztpraxis.io>")
-        .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .value_name("FILE")
-                .help("Sets config file")
-                .required(false)
-                .takes_value(true),
-        )
-        .get_matches();
-
-    let config_file;
-
-    match matches.value_of("config") {
-        Some(config_path) => match config_stem(config_path) {
-            Some(stem) => config_file = File::with_name(stem),
-            None => {
-                return Err(ConfigError::Message(String::from(
-                    "could not load from specified config path",
-                )));
-            }
-        },
-        None => {
-            config_file = File::with_name(app_name);
-        }
-    }
-
-    config.merge(config_file)?;
-
-    Ok(config)
-}